@@ -0,0 +1,2 @@
+-- calls counts requests, computeUnits is what plan limits are enforced against
+ALTER TABLE RpcPlans ADD COLUMN computeUnits BIGINT CHECK (computeUnits >= 0) NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- debug_* and trace_* compute units, counted against a separate quota of the paid plans
+ALTER TABLE RpcPlans ADD COLUMN traceComputeUnits BIGINT CHECK (traceComputeUnits >= 0) NOT NULL DEFAULT 0;
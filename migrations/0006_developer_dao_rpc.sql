@@ -0,0 +1,18 @@
+-- api keys are stored as a salted sha256, only a short prefix is kept in plain text to identify them
+ALTER TABLE Api ADD COLUMN keyPrefix VARCHAR(16);
+ALTER TABLE Api ADD COLUMN keySalt VARCHAR(64);
+ALTER TABLE Api ADD COLUMN keyHash VARCHAR(64);
+
+UPDATE Api SET
+    keyPrefix = left(apiKey, 12),
+    keySalt = md5(random()::text || clock_timestamp()::text);
+UPDATE Api SET keyHash = encode(sha256(convert_to(keySalt || apiKey, 'UTF8')), 'hex');
+
+ALTER TABLE Api DROP CONSTRAINT api_pkey;
+ALTER TABLE Api DROP COLUMN apiKey;
+ALTER TABLE Api ALTER COLUMN customerEmail SET NOT NULL;
+ALTER TABLE Api ALTER COLUMN keyPrefix SET NOT NULL;
+ALTER TABLE Api ALTER COLUMN keySalt SET NOT NULL;
+ALTER TABLE Api ALTER COLUMN keyHash SET NOT NULL;
+ALTER TABLE Api ADD PRIMARY KEY (keyPrefix);
+CREATE INDEX api_customer_email ON Api (customerEmail);
@@ -0,0 +1,5 @@
+-- labels and lifetimes of api keys, lastUsed lags behind by up to one flush of the call cache
+ALTER TABLE Api ADD COLUMN keyName VARCHAR(64) NOT NULL DEFAULT '';
+ALTER TABLE Api ADD COLUMN keyCreated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE Api ADD COLUMN keyLastUsed TIMESTAMPTZ;
+ALTER TABLE Api ADD COLUMN keyExpires TIMESTAMPTZ;
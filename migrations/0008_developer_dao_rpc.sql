@@ -0,0 +1,5 @@
+-- scopes of api keys, NULL lists don't restrict anything
+ALTER TABLE Api ADD COLUMN keyChains TEXT[];
+ALTER TABLE Api ADD COLUMN keyMethods TEXT[];
+ALTER TABLE Api ADD COLUMN keyDeniedMethods TEXT[];
+ALTER TABLE Api ADD COLUMN keyReadOnly BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- browser origins (domain or *.domain) and CIDR ranges an api key may be used from, NULL allows any
+ALTER TABLE Api ADD COLUMN keyOrigins TEXT[];
+ALTER TABLE Api ADD COLUMN keyIpRanges TEXT[];
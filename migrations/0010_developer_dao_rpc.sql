@@ -0,0 +1,7 @@
+-- rotated keys point to their successor and expire after a grace period.
+-- Keys rotated from one another share a lineage, the prefix of the first key, and draw from one rate limit
+ALTER TABLE Api ADD COLUMN keyLineage VARCHAR(16);
+UPDATE Api SET keyLineage = keyPrefix;
+ALTER TABLE Api ALTER COLUMN keyLineage SET NOT NULL;
+ALTER TABLE Api ADD COLUMN keySuccessor VARCHAR(16);
+CREATE INDEX api_key_lineage ON Api (keyLineage);
@@ -0,0 +1,5 @@
+-- optional monthly call cap per key, usage of each key is counted alongside the plan usage
+-- and reset with it
+ALTER TABLE Api ADD COLUMN keyCallCap BIGINT CHECK (keyCallCap > 0);
+ALTER TABLE Api ADD COLUMN keyCalls BIGINT NOT NULL DEFAULT 0 CHECK (keyCalls >= 0);
+ALTER TABLE Api ADD COLUMN keyComputeUnits BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,16 @@
+-- usage history, calls are rolled up per minute, hour and day (UTC) by key, chain, method and outcome.
+-- Rows outlive the keys they belong to so deleted keys still show up in the history of the account
+CREATE TABLE IF NOT EXISTS UsageRollups (
+    granularity VARCHAR(8) NOT NULL CHECK (granularity IN ('minute', 'hour', 'day')),
+    bucket TIMESTAMPTZ NOT NULL,
+    keyPrefix VARCHAR(16) NOT NULL,
+    customerEmail VARCHAR(255) NOT NULL,
+    chain VARCHAR(16) NOT NULL,
+    method VARCHAR(128) NOT NULL,
+    status VARCHAR(16) NOT NULL CHECK (status IN ('success', 'upstream_error')),
+    calls BIGINT NOT NULL CHECK (calls >= 0),
+    computeUnits BIGINT NOT NULL CHECK (computeUnits >= 0),
+    PRIMARY KEY (granularity, bucket, keyPrefix, chain, method, status)
+);
+
+CREATE INDEX IF NOT EXISTS usage_rollups_customer ON UsageRollups (customerEmail, granularity, bucket);
@@ -59,7 +59,24 @@ pub struct Payments<'a> {
 #[derive(FromRow, Debug, Serialize, Deserialize)]
 pub struct Api<'a> {
     pub customeremail: EmailAddress<'a>,
-    pub apikey: String,
+    pub keyprefix: String,
+    pub keysalt: String,
+    pub keyhash: String,
+    pub keyname: String,
+    pub keycreated: OffsetDateTime,
+    pub keylastused: Option<OffsetDateTime>,
+    pub keyexpires: Option<OffsetDateTime>,
+    pub keychains: Option<Vec<String>>,
+    pub keymethods: Option<Vec<String>>,
+    pub keydeniedmethods: Option<Vec<String>>,
+    pub keyreadonly: bool,
+    pub keyorigins: Option<Vec<String>>,
+    pub keyipranges: Option<Vec<String>>,
+    pub keylineage: String,
+    pub keysuccessor: Option<String>,
+    pub keycallcap: Option<i64>,
+    pub keycalls: i64,
+    pub keycomputeunits: i64,
 }
 
 #[derive(
@@ -121,24 +138,43 @@ impl Display for Plan {
 }
 
 impl Plan {
+    // Plan limits are in compute units, see middleware::compute_units for the method weights
     pub const FREE_TIER_LIMIT: u32 = 1_000_000;
-    // Free Tier: 1M requests per month
+    // Free Tier: 1M compute units per month
     pub const TIER_ONE: u32 = 5_000_000;
     pub const TIER_ONE_COST: f64 = 40.0;
-    // Tier 1: 5M requests per month
+    // Tier 1: 5M compute units per month
     // price: $40/mo
     pub const TIER_TWO: u32 = 30_000_000;
     pub const TIER_TWO_COST: f64 = 200.0;
-    // Tier 2: 30M requests per month
+    // Tier 2: 30M compute units per month
     // price: $200/mo
     pub const TIER_THREE: u32 = 150_000_000;
     pub const TIER_THREE_COST: f64 = 850.0;
-    // Tier 3: 150M requests per month
+    // Tier 3: 150M compute units per month
     // price: $850/mo
 
-    /// prorate user plan based on the number of calls made
+    // Requests per second of every api key, a batch counts as one request
+    pub const FREE_TIER_RATE: u32 = 25;
+    pub const TIER_ONE_RATE: u32 = 100;
+    pub const TIER_TWO_RATE: u32 = 300;
+    pub const TIER_THREE_RATE: u32 = 1_000;
+
+    // Websocket connections open at once across every api key of an account
+    pub const FREE_TIER_CONNECTIONS: usize = 10;
+    pub const TIER_ONE_CONNECTIONS: usize = 50;
+    pub const TIER_TWO_CONNECTIONS: usize = 250;
+    pub const TIER_THREE_CONNECTIONS: usize = 1_000;
+
+    // Trace quotas are in compute units of debug_* and trace_* methods, on top of the plan limit.
+    // Free Tier: no tracing
+    pub const TIER_ONE_TRACE: u32 = 250_000;
+    pub const TIER_TWO_TRACE: u32 = 2_500_000;
+    pub const TIER_THREE_TRACE: u32 = 15_000_000;
+
+    /// prorate user plan based on the number of compute units consumed
     /// this fn is pure, only calculates amount owed back
-    pub fn get_prorate_amount(&self, calls: i64) -> i64 {
+    pub fn get_prorate_amount(&self, compute_units: i64) -> i64 {
         let amount_per_plan = match self {
             Plan::Free => 0,
             Plan::Tier1 => 800,
@@ -146,8 +182,8 @@ impl Plan {
             Plan::Tier3 => 566,
         };
         // because the cost basis is per million units
-        // Prorate = (PlanLimit - UsedCalls) / 1_000_000
-        let mils_left = (self.get_plan_limit() as i64 - calls) / 1_000_000;
+        // Prorate = (PlanLimit - UsedComputeUnits) / 1_000_000
+        let mils_left = (self.get_plan_limit() as i64 - compute_units) / 1_000_000;
         mils_left * amount_per_plan
     }
 
@@ -160,6 +196,7 @@ impl Plan {
         }
     }
 
+    /// monthly allowance in compute units
     pub fn get_plan_limit(&self) -> u32 {
         match self {
             Plan::Free => Self::FREE_TIER_LIMIT,
@@ -168,6 +205,36 @@ impl Plan {
             Plan::Tier3 => Self::TIER_THREE,
         }
     }
+
+    /// requests per second allowed per api key
+    pub fn get_rate_limit(&self) -> u32 {
+        match self {
+            Plan::Free => Self::FREE_TIER_RATE,
+            Plan::Tier1 => Self::TIER_ONE_RATE,
+            Plan::Tier2 => Self::TIER_TWO_RATE,
+            Plan::Tier3 => Self::TIER_THREE_RATE,
+        }
+    }
+
+    /// websocket connections the account may hold open
+    pub fn get_connection_limit(&self) -> usize {
+        match self {
+            Plan::Free => Self::FREE_TIER_CONNECTIONS,
+            Plan::Tier1 => Self::TIER_ONE_CONNECTIONS,
+            Plan::Tier2 => Self::TIER_TWO_CONNECTIONS,
+            Plan::Tier3 => Self::TIER_THREE_CONNECTIONS,
+        }
+    }
+
+    /// monthly allowance for debug_* and trace_* methods in compute units, 0 if the plan can't trace
+    pub fn get_trace_limit(&self) -> u32 {
+        match self {
+            Plan::Free => 0,
+            Plan::Tier1 => Self::TIER_ONE_TRACE,
+            Plan::Tier2 => Self::TIER_TWO_TRACE,
+            Plan::Tier3 => Self::TIER_THREE_TRACE,
+        }
+    }
 }
 
 impl Display for Chain {
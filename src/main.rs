@@ -1,7 +1,11 @@
 use crate::middleware::{
-    jwt_auth::verify_jwt, rpc_service::validate_subscription_and_update_user_calls,
+    allowlist::relay_cors,
+    jwt_auth::verify_jwt,
+    rpc_service::{sync_call_cache, validate_subscription_and_update_user_calls},
 };
 use crate::routes::payment::{cancel, downgrade, upgrade};
+use crate::routes::relayer::health::probe_upstreams;
+use crate::routes::relayer::queue::get_ws_sessions;
 use crate::routes::relayer::websockets::ws_handler;
 use crate::routes::token_queries::{
     aggregate_balances, aggregate_single_token_bals, aggregate_token_bals_for_user,
@@ -10,7 +14,9 @@ use crate::routes::token_queries::{
 use crate::routes::types::{EmailLogin, JWTKey};
 use crate::routes::{
     activate::activate_account,
-    api_keys::{delete_key, generate_api_keys, get_all_api_keys},
+    api_keys::{
+        delete_key, generate_api_keys, get_all_api_keys, rename_key, rotate_key, set_key_cap,
+    },
     login::user_login,
     recovery::{recover_password_email, update_password},
     register::register_user,
@@ -24,14 +30,17 @@ use axum::{
     http::{StatusCode, header},
     middleware::from_fn,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{get, post, put},
 };
 use database::types::Database;
 // use middleware::rpc_service::{RpcAuthErrors, refill_calls_and_renew_plans};
 use mimalloc::MiMalloc;
+use routes::export::{export_payments, export_usage};
 use routes::login::{refresh, user_login_siwe};
 use routes::payment::{get_calls_and_balance, get_payments, process_ethereum_payment};
 use routes::siwe::{get_siwe_nonce, jwt_get_siwe_nonce, siwe_add_wallet};
+use routes::usage::{get_top_methods, get_usage_series};
+use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
 use tracing::info;
@@ -57,6 +66,9 @@ async fn main() {
         .with_target(false)
         .init();
 
+    tokio::spawn(sync_call_cache());
+    tokio::spawn(probe_upstreams());
+
     let origin = if cfg!(feature = "dev") {
         "http://localhost:5173"
     } else {
@@ -66,19 +78,31 @@ async fn main() {
     let cors_api = CorsLayer::new()
         .allow_credentials(true)
         .allow_origin(origin.parse::<HeaderValue>().unwrap())
-        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::PUT,
+            Method::DELETE,
+        ])
         .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::COOKIE]);
 
     #[cfg(feature = "dev")]
     let relayer = Router::new()
+        .route("/rpc/{chain}", post(route_call))
         .route("/rpc/{chain}/{api_key}", post(route_call))
-        .route("/ws/{chain}/{api_key}", axum::routing::any(ws_handler));
+        .route("/ws/{chain}", axum::routing::any(ws_handler))
+        .route("/ws/{chain}/{api_key}", axum::routing::any(ws_handler))
+        .layer(from_fn(relay_cors));
 
     #[cfg(not(feature = "dev"))]
     let relayer = Router::new()
+        .route("/rpc/{chain}", post(route_call))
         .route("/rpc/{chain}/{api_key}", post(route_call))
+        .route("/ws/{chain}", axum::routing::any(ws_handler))
         .route("/ws/{chain}/{api_key}", axum::routing::any(ws_handler))
-        .route_layer(from_fn(validate_subscription_and_update_user_calls));
+        .route_layer(from_fn(validate_subscription_and_update_user_calls))
+        .layer(from_fn(relay_cors));
 
     let token_queries = Router::new()
         .route(
@@ -100,7 +124,14 @@ async fn main() {
 
     let api_keys = Router::new()
         .route("/api/keys", get(get_all_api_keys).post(generate_api_keys))
-        .route("/api/keys/{key}", delete(delete_key))
+        .route("/api/keys/{prefix}", delete(delete_key).patch(rename_key))
+        .route("/api/keys/{prefix}/rotate", post(rotate_key))
+        .route("/api/keys/{prefix}/cap", put(set_key_cap))
+        .route("/api/usage", get(get_usage_series))
+        .route("/api/usage/methods", get(get_top_methods))
+        .route("/api/export/usage", get(export_usage))
+        .route("/api/export/payments", get(export_payments))
+        .route("/api/ws/sessions", get(get_ws_sessions))
         .route_layer(from_fn(verify_jwt));
 
     let payments = Router::new()
@@ -139,5 +170,11 @@ async fn main() {
 
     info!("Initialized D_D RPC on 0.0.0.0:3000");
     let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // client addresses are needed for the ip allowlists of api keys
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
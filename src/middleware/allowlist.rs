@@ -0,0 +1,302 @@
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+            ORIGIN, REFERER, VARY,
+        },
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::LazyLock,
+};
+
+/// Proxies in front of the relay, each appends the address it saw to `X-Forwarded-For`.
+/// With none the address of the connection is used.
+pub static FORWARDED_HOPS: LazyLock<usize> = LazyLock::new(|| {
+    dotenvy::var("FORWARDED_HOPS")
+        .ok()
+        .and_then(|hops| hops.parse().ok())
+        .unwrap_or(0)
+});
+
+/// A domain allowed to use a key from the browser, `*.example.com` matches every subdomain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct OriginPattern {
+    host: String,
+    subdomains: bool,
+}
+
+impl OriginPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self.subdomains {
+            true => host
+                .strip_suffix(&self.host)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            false => host == self.host,
+        }
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = AllowlistError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // full origins are accepted as well, only the host is kept
+        let value = value.trim().to_ascii_lowercase();
+        let value = value
+            .split_once("://")
+            .map_or(value.as_str(), |(_, rest)| rest);
+        let value = value.trim_end_matches('/');
+        let (host, subdomains) = match value.strip_prefix("*.") {
+            Some(host) => (host, true),
+            None => (value, false),
+        };
+        if host.is_empty()
+            || !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-'))
+        {
+            Err(AllowlistError::Origin(value.to_string()))?
+        }
+        Ok(OriginPattern {
+            host: host.to_string(),
+            subdomains,
+        })
+    }
+}
+
+impl fmt::Display for OriginPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.subdomains {
+            true => write!(f, "*.{}", self.host),
+            false => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// An address or CIDR range allowed to use a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                masked(range.to_bits().into(), ip.to_bits().into(), self.prefix, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                masked(range.to_bits(), ip.to_bits(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(range: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    prefix == 0 || (range >> shift) == (ip >> shift)
+}
+
+impl FromStr for IpRange {
+    type Err = AllowlistError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || AllowlistError::IpRange(value.to_string());
+        let (addr, prefix) = value.trim().split_once('/').unwrap_or((value.trim(), ""));
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix.parse().map_err(|_| invalid())?,
+        };
+        if prefix > bits {
+            Err(invalid())?
+        }
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+macro_rules! string_conversions {
+    ($($ty:ty),*) => {$(
+        impl TryFrom<String> for $ty {
+            type Error = AllowlistError;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                value.parse()
+            }
+        }
+
+        impl From<$ty> for String {
+            fn from(value: $ty) -> Self {
+                value.to_string()
+            }
+        }
+    )*};
+}
+
+string_conversions!(OriginPattern, IpRange);
+
+#[derive(Debug, thiserror::Error)]
+pub enum AllowlistError {
+    #[error("{0} is not a domain or *.domain")]
+    Origin(String),
+    #[error("{0} is not an IP address or CIDR range")]
+    IpRange(String),
+}
+
+/// host of the page a browser request comes from, `Origin` is preferred over `Referer`
+pub fn request_host(headers: &HeaderMap) -> Option<&str> {
+    let url = headers
+        .get(ORIGIN)
+        .or_else(|| headers.get(REFERER))?
+        .to_str()
+        .ok()?;
+    let authority = url.split_once("://")?.1.split('/').next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    // strips the port, IPv6 hosts are bracketed
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    (!host.is_empty()).then_some(host)
+}
+
+/// address of the client, read from `X-Forwarded-For` when the relay runs behind proxies
+pub fn client_ip(
+    headers: &HeaderMap,
+    connect_info: Option<&ConnectInfo<SocketAddr>>,
+) -> Option<IpAddr> {
+    let hops = *FORWARDED_HOPS;
+    if hops == 0 {
+        return connect_info.map(|ConnectInfo(addr)| addr.ip());
+    }
+    // proxies append, so only the entries written by our own proxies can be trusted
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    forwarded
+        .len()
+        .checked_sub(hops)
+        .and_then(|i| forwarded[i].parse().ok())
+}
+
+/// CORS of the relay routes. Keys travel in headers a preflight doesn't carry, so preflights
+/// are answered for every origin. Responses are only exposed to origins the key allows,
+/// the metering middleware marks them with [`allow_origin`].
+pub async fn relay_cors(request: Request, next: Next) -> Response {
+    let Some(origin) = request.headers().get(ORIGIN).cloned() else {
+        return next.run(request).await;
+    };
+    if request.method() == Method::OPTIONS {
+        let mut res = StatusCode::NO_CONTENT.into_response();
+        let headers = res.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, POST"),
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_static("content-type, authorization, x-api-key"),
+        );
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(86400));
+        headers.insert(VARY, HeaderValue::from_static("origin"));
+        return res;
+    }
+
+    let mut res = next.run(request).await;
+    // no keys are checked in dev
+    if cfg!(feature = "dev") {
+        allow_origin(res.headers_mut(), origin);
+    }
+    res.headers_mut()
+        .append(VARY, HeaderValue::from_static("origin"));
+    res
+}
+
+/// exposes a relay response to the page it was requested from
+pub fn allow_origin(headers: &mut HeaderMap, origin: HeaderValue) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(
+            "x-ratelimit-limit, x-ratelimit-remaining, x-ratelimit-reset, retry-after",
+        ),
+    );
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{IpRange, OriginPattern, request_host};
+    use axum::http::{HeaderMap, HeaderValue, header::ORIGIN};
+
+    #[test]
+    fn origins_match_hosts() {
+        let exact: OriginPattern = "https://App.example.com/".parse().unwrap();
+        assert!(exact.matches("app.example.com"));
+        assert!(!exact.matches("example.com"));
+
+        let wildcard: OriginPattern = "*.example.com".parse().unwrap();
+        assert!(wildcard.matches("app.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+        assert!("*.exa*mple.com".parse::<OriginPattern>().is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ORIGIN,
+            HeaderValue::from_static("https://app.example.com:8443"),
+        );
+        assert_eq!(request_host(&headers), Some("app.example.com"));
+        headers.insert(ORIGIN, HeaderValue::from_static("null"));
+        assert_eq!(request_host(&headers), None);
+    }
+
+    #[test]
+    fn ranges_contain_addresses() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains("10.1.200.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        // v4 clients of a dual stack listener
+        assert!(range.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let single: IpRange = "2001:db8::1".parse().unwrap();
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+        assert!(
+            "0.0.0.0/0"
+                .parse::<IpRange>()
+                .unwrap()
+                .contains("1.2.3.4".parse().unwrap())
+        );
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::routes::{
+    api_keys::KeyScope,
+    relayer::{
+        logs::log_chunks,
+        policy::denial,
+        types::{JsonRpcPayload, JsonRpcRequest, PoktChains},
+    },
+};
+use std::{collections::HashMap, sync::LazyLock};
+
+/// Weight of any method that isn't listed below
+pub const DEFAULT_COMPUTE_UNITS: i64 = 1;
+/// Weight of `debug_*` and `trace_*` methods that aren't listed below
+pub const TRACE_COMPUTE_UNITS: i64 = 50;
+
+/// Compute units charged per method, roughly proportional to the work the upstream node does
+static METHOD_WEIGHTS: LazyLock<HashMap<&'static str, i64>> = LazyLock::new(|| {
+    let mut map = HashMap::new();
+    // state reads that execute the EVM
+    map.insert("eth_call", 2);
+    map.insert("eth_estimateGas", 2);
+    map.insert("eth_createAccessList", 2);
+    map.insert("eth_feeHistory", 2);
+    // block and receipt lookups
+    map.insert("eth_getBlockByNumber", 2);
+    map.insert("eth_getBlockByHash", 2);
+    map.insert("eth_getTransactionReceipt", 2);
+    map.insert("eth_getBlockReceipts", 10);
+    map.insert("eth_getProof", 10);
+    // range scans
+    map.insert("eth_getLogs", 10);
+    map.insert("eth_newFilter", 10);
+    map.insert("eth_getFilterLogs", 10);
+    // broadcasts
+    map.insert("eth_sendRawTransaction", 5);
+    map.insert("eth_subscribe", 5);
+    // tracing
+    map.insert("debug_traceTransaction", 50);
+    map.insert("debug_traceCall", 50);
+    map.insert("debug_traceBlockByNumber", 100);
+    map.insert("debug_traceBlockByHash", 100);
+    map.insert("trace_block", 100);
+    map.insert("trace_replayBlockTransactions", 100);
+    map
+});
+
+/// Usage of a single request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metered {
+    pub calls: i64,
+    pub compute_units: i64,
+    // the part of compute_units spent on tracing, also drawn from the trace quota
+    pub trace_compute_units: i64,
+}
+
+impl Metered {
+    pub fn of(chain: PoktChains, payload: &JsonRpcPayload) -> Self {
+        Metered {
+            calls: payload.len() as i64,
+            compute_units: payload_compute_units(chain, payload),
+            trace_compute_units: payload
+                .requests()
+                .iter()
+                .filter(|request| is_trace_method(&request.method))
+                .map(|request| method_compute_units(&request.method))
+                .sum(),
+        }
+    }
+
+    /// usage of the calls that pass the policy, denied calls are answered for free
+    pub fn of_allowed(
+        chain: PoktChains,
+        scope: Option<&KeyScope>,
+        payload: &JsonRpcPayload,
+    ) -> Self {
+        match payload {
+            JsonRpcPayload::Single(request) if denial(chain, scope, request).is_some() => {
+                Metered::default()
+            }
+            JsonRpcPayload::Single(_) => Metered::of(chain, payload),
+            JsonRpcPayload::Batch(batch) => {
+                let allowed: Vec<&JsonRpcRequest> = batch
+                    .iter()
+                    .filter(|request| denial(chain, scope, request).is_none())
+                    .collect();
+                Metered {
+                    calls: allowed.len() as i64,
+                    compute_units: allowed
+                        .iter()
+                        .map(|request| method_compute_units(&request.method))
+                        .sum(),
+                    trace_compute_units: allowed
+                        .iter()
+                        .filter(|request| is_trace_method(&request.method))
+                        .map(|request| method_compute_units(&request.method))
+                        .sum(),
+                }
+            }
+        }
+    }
+
+    /// the part drawn from the plan limit, tracing is paid from the trace quota alone
+    pub fn plan_compute_units(&self) -> i64 {
+        self.compute_units - self.trace_compute_units
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Metered::default()
+    }
+}
+
+/// heavy methods only available on plans with a trace quota
+pub fn is_trace_method(method: &str) -> bool {
+    method.starts_with("debug_") || method.starts_with("trace_")
+}
+
+/// compute units charged for a single method
+pub fn method_compute_units(method: &str) -> i64 {
+    match METHOD_WEIGHTS.get(method) {
+        Some(weight) => *weight,
+        None if is_trace_method(method) => TRACE_COMPUTE_UNITS,
+        None => DEFAULT_COMPUTE_UNITS,
+    }
+}
+
+/// compute units charged for a payload, every call of a batch is priced on its own.
+/// Only single calls are split, so only they are billed per chunk.
+pub fn payload_compute_units(chain: PoktChains, payload: &JsonRpcPayload) -> i64 {
+    match payload {
+        JsonRpcPayload::Single(request) => split_compute_units(chain, request),
+        JsonRpcPayload::Batch(batch) => batch
+            .iter()
+            .map(|request| method_compute_units(&request.method))
+            .sum(),
+    }
+}
+
+/// single `eth_getLogs` calls that are split into chunks pay for every chunk
+fn split_compute_units(chain: PoktChains, request: &JsonRpcRequest) -> i64 {
+    let chunks = log_chunks(chain, request).map_or(1, |chunks| chunks.len() as i64);
+    method_compute_units(&request.method) * chunks
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn prices_methods() {
+        assert_eq!(
+            method_compute_units("eth_blockNumber"),
+            DEFAULT_COMPUTE_UNITS
+        );
+        assert_eq!(method_compute_units("eth_getLogs"), 10);
+        assert_eq!(method_compute_units("trace_block"), 100);
+        assert_eq!(
+            method_compute_units("debug_getRawBlock"),
+            TRACE_COMPUTE_UNITS
+        );
+    }
+
+    #[test]
+    fn prices_batches() {
+        let payload: JsonRpcPayload = serde_json::from_value(json!([
+            {"jsonrpc":"2.0","method":"eth_blockNumber","id": 1},
+            {"jsonrpc":"2.0","method":"eth_getLogs","params":[{}],"id": 2},
+            {"jsonrpc":"2.0","method":"debug_traceTransaction","params":["0x0"],"id": 3}
+        ]))
+        .unwrap();
+        assert_eq!(
+            payload_compute_units(PoktChains::Anvil, &payload),
+            1 + 10 + 50
+        );
+        assert_eq!(
+            Metered::of(PoktChains::Anvil, &payload),
+            Metered {
+                calls: 3,
+                compute_units: 61,
+                trace_compute_units: 50
+            }
+        );
+
+        // denied calls are answered without being charged
+        let scope = KeyScope {
+            denied_methods: Some(vec!["debug_*".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            Metered::of_allowed(PoktChains::Anvil, Some(&scope), &payload),
+            Metered {
+                calls: 2,
+                compute_units: 11,
+                trace_compute_units: 0
+            }
+        );
+        let payload: JsonRpcPayload =
+            serde_json::from_value(json!({"jsonrpc":"2.0","method":"admin_peers","id": 1}))
+                .unwrap();
+        assert!(Metered::of_allowed(PoktChains::Anvil, None, &payload).is_empty());
+    }
+}
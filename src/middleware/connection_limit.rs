@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+/// Open websocket connections of every api key and account
+pub static CONNECTION_LIMITER: LazyLock<ConnectionLimiter> =
+    LazyLock::new(ConnectionLimiter::default);
+
+/// Websocket connections a single api key may hold open, the plan of its account may allow fewer
+pub static CONNECTIONS_PER_KEY: LazyLock<usize> = LazyLock::new(|| {
+    dotenvy::var("WS_CONNECTIONS_PER_KEY")
+        .ok()
+        .and_then(|connections| connections.parse().ok())
+        .unwrap_or(100)
+});
+
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    // key prefix => open connections
+    keys: Mutex<HashMap<String, usize>>,
+    // account email => open connections of all its keys
+    accounts: Mutex<HashMap<String, usize>>,
+}
+
+impl ConnectionLimiter {
+    /// takes a slot of the key and of its account, None if either has none left
+    pub fn acquire(
+        &'static self,
+        prefix: &str,
+        email: &str,
+        per_key: usize,
+        per_account: usize,
+    ) -> Option<ConnectionSlot> {
+        let mut keys = self.keys.lock().unwrap();
+        let mut accounts = self.accounts.lock().unwrap();
+        let key = keys.entry(prefix.to_string()).or_default();
+        let account = accounts.entry(email.to_string()).or_default();
+        if *key >= per_key || *account >= per_account {
+            // entries of keys that hold no connection don't linger
+            if *key == 0 {
+                keys.remove(prefix);
+            }
+            if *account == 0 {
+                accounts.remove(email);
+            }
+            return None;
+        }
+        *key += 1;
+        *account += 1;
+        Some(ConnectionSlot {
+            limiter: self,
+            prefix: prefix.to_string(),
+            email: email.to_string(),
+        })
+    }
+
+    fn release(&self, prefix: &str, email: &str) {
+        for (open, id) in [(&self.keys, prefix), (&self.accounts, email)] {
+            let mut open = open.lock().unwrap();
+            if let Some(count) = open.get_mut(id) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    open.remove(id);
+                }
+            }
+        }
+    }
+}
+
+/// A websocket connection counted against its key and account, released when dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limiter: &'static ConnectionLimiter,
+    prefix: String,
+    email: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limiter.release(&self.prefix, &self.email);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::ConnectionLimiter;
+    use std::sync::LazyLock;
+
+    static LIMITER: LazyLock<ConnectionLimiter> = LazyLock::new(ConnectionLimiter::default);
+
+    #[test]
+    fn slots_are_capped_per_key_and_account() {
+        let first = LIMITER.acquire("dd_first", "a@b.c", 2, 3).unwrap();
+        let _second = LIMITER.acquire("dd_first", "a@b.c", 2, 3).unwrap();
+        assert!(LIMITER.acquire("dd_first", "a@b.c", 2, 3).is_none());
+
+        // other keys of the account share its cap
+        let _third = LIMITER.acquire("dd_other", "a@b.c", 2, 3).unwrap();
+        assert!(LIMITER.acquire("dd_other", "a@b.c", 2, 3).is_none());
+
+        drop(first);
+        let _fourth = LIMITER.acquire("dd_other", "a@b.c", 2, 3).unwrap();
+        assert!(LIMITER.acquire("dd_first", "a@b.c", 2, 3).is_none());
+    }
+}
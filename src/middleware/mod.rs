@@ -1,2 +1,6 @@
+pub mod allowlist;
+pub mod compute_units;
+pub mod connection_limit;
 pub mod jwt_auth;
+pub mod rate_limit;
 pub mod rpc_service;
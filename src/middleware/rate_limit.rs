@@ -0,0 +1,109 @@
+use axum::http::{HeaderMap, HeaderValue, header::RETRY_AFTER};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex, RwLock},
+    time::Instant,
+};
+
+/// Token buckets of every api key, refilled continuously at the rate of the key's plan
+pub static RATE_LIMITER: LazyLock<RateLimiter> = LazyLock::new(RateLimiter::default);
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Outcome of a rate limit check, rendered as `X-RateLimit-*` headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // seconds until the bucket is full again
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from(self.reset));
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.reset.max(1)));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: RwLock<HashMap<String, Arc<Mutex<TokenBucket>>>>,
+}
+
+impl RateLimiter {
+    /// takes `cost` tokens from the key's bucket, a burst of up to one second worth of requests is allowed
+    pub fn check(&self, key: &str, per_second: u32, cost: u32) -> RateLimit {
+        let bucket = self.bucket(key, per_second);
+        let mut bucket = bucket.lock().unwrap();
+
+        let rate = per_second as f64;
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.refilled = now;
+
+        let allowed = bucket.tokens >= cost as f64;
+        if allowed {
+            bucket.tokens -= cost as f64;
+        }
+        RateLimit {
+            allowed,
+            limit: per_second,
+            remaining: bucket.tokens.max(0.0) as u32,
+            reset: ((rate - bucket.tokens) / rate).ceil() as u64,
+        }
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.buckets.write().unwrap().remove(key);
+    }
+
+    fn bucket(&self, key: &str, per_second: u32) -> Arc<Mutex<TokenBucket>> {
+        if let Some(bucket) = self.buckets.read().unwrap().get(key) {
+            return bucket.clone();
+        }
+        self.buckets
+            .write()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(TokenBucket {
+                    tokens: per_second as f64,
+                    refilled: Instant::now(),
+                }))
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::RateLimiter;
+
+    #[test]
+    fn buckets_drain_and_limit() {
+        let limiter = RateLimiter::default();
+        let first = limiter.check("key", 10, 8);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 2);
+
+        // the cost must fit into the bucket as a whole
+        let second = limiter.check("key", 10, 5);
+        assert!(!second.allowed);
+        assert_eq!(second.remaining, 2);
+        assert_eq!(second.reset, 1);
+
+        // other keys have their own bucket
+        assert!(limiter.check("other", 10, 10).allowed);
+    }
+}
@@ -1,70 +1,564 @@
 use crate::{
     database::types::{Plan, RELATIONAL_DATABASE},
-    routes::types::EmailAddress,
+    middleware::{
+        allowlist::{allow_origin, client_ip, request_host},
+        compute_units::Metered,
+        rate_limit::RATE_LIMITER,
+    },
+    routes::{
+        api_keys::{ApiKeyHash, KeyScope, visible_prefix},
+        relayer::{
+            errors::{
+                INTERNAL_ERROR, JsonRpcFailure, LIMIT_EXCEEDED, METHOD_NOT_SUPPORTED, RequestIds,
+                RpcError, UNAUTHORIZED, WithIds,
+            },
+            router::{
+                AccountUsage, CALL_CACHE, CachedKey, CachedKeyRow, MAX_BODY_SIZE, RouterErrors,
+                parse_payload,
+            },
+            types::{JsonRpcPayload, PoktChains},
+        },
+        types::EmailAddress,
+        usage::{UsageSource, flush_usage, prune_usage},
+    },
 };
 use axum::{
+    body::Body,
     extract::{Path, Request},
+    http::{
+        HeaderMap, Method, StatusCode,
+        header::{AUTHORIZATION, ORIGIN},
+    },
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
 use thiserror::Error;
 use time::OffsetDateTime;
 use tracing::{info, warn};
 
 pub struct Credits<'a> {
-    calls: i64,
+    keysalt: String,
+    keyhash: String,
+    keyexpires: Option<OffsetDateTime>,
+    keychains: Option<Vec<String>>,
+    keymethods: Option<Vec<String>>,
+    keydeniedmethods: Option<Vec<String>>,
+    keyreadonly: bool,
+    keyorigins: Option<Vec<String>>,
+    keyipranges: Option<Vec<String>>,
+    keylineage: String,
+    keycallcap: Option<i64>,
+    keycalls: i64,
+    compute_units: i64,
+    trace_compute_units: i64,
     email: EmailAddress<'a>,
     plan: Plan,
     expires: OffsetDateTime,
 }
 
-#[tracing::instrument(skip(request))]
+/// The api key of a request, headers are preferred so keys stay out of access logs
+pub fn request_api_key<'a>(
+    headers: &'a HeaderMap,
+    path: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    header("x-api-key")
+        .or_else(|| header(AUTHORIZATION.as_str())?.strip_prefix("Bearer "))
+        .map(str::trim)
+        .or(path.get("api_key").map(String::as_str))
+        .filter(|key| !key.is_empty())
+}
+
+// skips everything, the path can hold the api key
+#[tracing::instrument(skip_all)]
 pub async fn validate_subscription_and_update_user_calls(
-    Path(key): Path<[String; 2]>,
+    path: Path<HashMap<String, String>>,
     request: Request,
     next: Next,
-) -> Result<impl IntoResponse, RpcAuthErrors> {
-    let db_connection = RELATIONAL_DATABASE.get().unwrap();
-    let mut sub_info: Credits = sqlx::query_as!(
-        Credits,
-        r#"
-            SELECT email, calls, plan as "plan!: Plan", expires
-            FROM RpcPlans
+) -> Response {
+    let origin = request.headers().get(ORIGIN).cloned();
+    let mut origin_allowed = false;
+    let mut res = meter_request(path, request, next, &mut origin_allowed)
+        .await
+        .unwrap_or_else(IntoResponse::into_response);
+    // errors are exposed as well once the key is known to allow the page
+    if let Some(origin) = origin.filter(|_| origin_allowed) {
+        allow_origin(res.headers_mut(), origin);
+    }
+    res
+}
+
+async fn meter_request(
+    Path(path): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+    origin_allowed: &mut bool,
+) -> Result<Response, RpcError> {
+    let api_key = request_api_key(request.headers(), &path)
+        .ok_or(RpcAuthErrors::InvalidApiKey)
+        .with_ids(&RequestIds::Unknown)?
+        .to_string();
+
+    let chain = |ids: &RequestIds| {
+        path.get("chain")
+            .ok_or(RouterErrors::DestinationError)
+            .and_then(|chain| chain.parse::<PoktChains>().map_err(RouterErrors::from))
+            .with_ids(ids)
+    };
+
+    // websocket upgrades carry no body and are free, the session meters every request it relays
+    let (mut request, ids, chain) = if request.method() == Method::GET {
+        let chain = chain(&RequestIds::Unknown)?;
+        (request, RequestIds::Unknown, chain)
+    } else {
+        let (mut parts, body) = request.into_parts();
+        let bytes = axum::body::to_bytes(body, MAX_BODY_SIZE)
+            .await
+            .map_err(|_| RouterErrors::BodyTooLarge(MAX_BODY_SIZE))
+            .with_ids(&RequestIds::Unknown)?;
+        let payload = parse_payload(&bytes).with_ids(&RequestIds::Unknown)?;
+        let ids = RequestIds::from(&payload);
+        let chain = chain(&ids)?;
+        parts.extensions.insert(payload);
+        let request = Request::from_parts(parts, Body::from(bytes));
+        (request, ids, chain)
+    };
+
+    let key = cached_key(&api_key).await.with_ids(&ids)?;
+    if key.is_expired() {
+        Err(RpcAuthErrors::KeyExpired).with_ids(&ids)?
+    }
+    // browser and network restrictions apply before anything is metered
+    if !key.scope.allows_origin(request_host(request.headers())) {
+        Err(RpcAuthErrors::OriginNotAllowed).with_ids(&ids)?
+    }
+    *origin_allowed = true;
+    let ip = client_ip(request.headers(), request.extensions().get());
+    if !key.scope.allows_ip(ip) {
+        Err(RpcAuthErrors::IpNotAllowed).with_ids(&ids)?
+    }
+    if !key.scope.allows_chain(chain) {
+        Err(RpcAuthErrors::ChainNotInScope(chain)).with_ids(&ids)?
+    }
+    key.touch();
+    // methods are checked against the scope where calls are relayed
+    request.extensions_mut().insert(key.scope.clone());
+
+    // rate limited requests are rejected before they are metered.
+    // A batch is one request here, its calls are already priced in compute units
+    let plan = key.usage.state().plan;
+    let rate = RATE_LIMITER.check(&key.lineage, plan.get_rate_limit(), 1);
+
+    // plan limits are in compute units, a batch must fit into what's left as a whole.
+    // The cap of the key is checked first so a capped key can't drain the account.
+    // Calls the policy denies are answered by the router without being metered
+    let metered = match request.extensions().get::<JsonRpcPayload>() {
+        Some(payload) => Metered::of_allowed(chain, Some(&key.scope), payload),
+        None => Metered::default(),
+    };
+    let admitted = if !rate.allowed {
+        Err(RpcAuthErrors::RateLimited)
+    } else if metered.is_empty() {
+        Ok(())
+    } else {
+        key.try_consume(&metered)
+    };
+    // rejections carry the rate limit as well, so clients can tell the limits apart
+    if let Err(e) = admitted {
+        let mut res = RpcError::new(e, ids).into_response();
+        rate.apply(res.headers_mut());
+        return Ok(res);
+    }
+    request.extensions_mut().insert(UsageSource {
+        prefix: visible_prefix(&api_key).to_string(),
+        email: key.usage.email.clone(),
+    });
+    // websocket connections are capped by the plan of the key
+    request.extensions_mut().insert(key);
+
+    let mut res = next.run(request).await;
+    rate.apply(res.headers_mut());
+    Ok(res)
+}
+
+/// usage of the account owning the key, loaded into the call cache on first use
+/// keys are looked up by their prefix and verified against the stored hash
+async fn cached_key(key: &str) -> Result<CachedKey, RpcAuthErrors> {
+    let prefix = visible_prefix(key);
+    let cached = match CALL_CACHE.count_ref(prefix) {
+        Some(cached) => cached,
+        None => {
+            let sub_info: Credits = sqlx::query_as!(
+                Credits,
+                r#"
+            SELECT
+                keySalt,
+                keyHash,
+                keyExpires,
+                keyChains,
+                keyMethods,
+                keyDeniedMethods,
+                keyReadOnly,
+                keyOrigins,
+                keyIpRanges,
+                keyLineage,
+                keyCallCap,
+                keyCalls,
+                email,
+                computeUnits AS compute_units,
+                traceComputeUnits AS trace_compute_units,
+                plan as "plan!: Plan",
+                expires
+            FROM
+                Api
+            INNER JOIN
+                RpcPlans
+            ON
+                Api.customerEmail = RpcPlans.email
             WHERE
-            email = (SELECT customerEmail FROM Api WHERE apiKey = $1) 
+                Api.keyPrefix = $1
         "#,
-        key.get(1).ok_or_else(|| RpcAuthErrors::InvalidApiKey)?
+                prefix
+            )
+            .fetch_optional(RELATIONAL_DATABASE.get().unwrap())
+            .await?
+            .ok_or_else(|| RpcAuthErrors::InvalidApiKey)?;
+
+            CALL_CACHE.insert(CachedKeyRow {
+                prefix: prefix.to_string(),
+                hash: ApiKeyHash {
+                    salt: sub_info.keysalt,
+                    hash: sub_info.keyhash,
+                },
+                expires: sub_info.keyexpires,
+                scope: KeyScope::from_columns(
+                    sub_info.keychains,
+                    sub_info.keymethods,
+                    sub_info.keydeniedmethods,
+                    sub_info.keyreadonly,
+                    sub_info.keyorigins,
+                    sub_info.keyipranges,
+                ),
+                lineage: sub_info.keylineage,
+                cap: sub_info.keycallcap,
+                calls: sub_info.keycalls,
+                usage: AccountUsage::new(
+                    sub_info.email.0.into_owned(),
+                    sub_info.plan,
+                    sub_info.expires,
+                    sub_info.compute_units,
+                    sub_info.trace_compute_units,
+                ),
+            })
+        }
+    };
+    if !cached.hash.verifies(key) {
+        Err(RpcAuthErrors::InvalidApiKey)?
+    }
+    Ok(cached)
+}
+
+/// How often metered usage is written to Postgres
+pub static CACHE_FLUSH_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        dotenvy::var("CACHE_FLUSH_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(5),
+    )
+});
+
+/// How often plans, limits and keys are reloaded from Postgres
+pub static CACHE_RELOAD_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        dotenvy::var("CACHE_RELOAD_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(60),
     )
-    .fetch_optional(db_connection)
-    .await?
-    .ok_or_else(|| RpcAuthErrors::InvalidApiKey)?;
-
-    if OffsetDateTime::now_utc() > sub_info.expires {
-        // This behavior might be a little counter intuitive, but it's good for the user.
-        // Even if the plan is expired, let the call through since it will downgrade to free if they can't pay
-        tokio::spawn(async move {
-            if let Err(e) = refill_calls_and_renew_plans().await {
-                info!("Failed to refill calls or reset plan for users:\n {}", e);
+});
+
+/// Background task that keeps the call cache and Postgres in sync.
+/// Usage is flushed as aggregated deltas, plans are renewed and reloaded less often.
+pub async fn sync_call_cache() {
+    let mut flush = tokio::time::interval(*CACHE_FLUSH_INTERVAL);
+    let mut reload = tokio::time::interval(*CACHE_RELOAD_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = flush.tick() => {
+                if let Err(e) = flush_call_cache().await {
+                    warn!("Failed to flush metered calls: {e}");
+                }
+                if let Err(e) = flush_usage().await {
+                    warn!("Failed to flush usage rollups: {e}");
+                }
             }
-        });
-        sub_info.calls = 0;
+            _ = reload.tick() => {
+                if let Err(e) = flush_call_cache().await {
+                    warn!("Failed to flush metered calls: {e}");
+                }
+                if CALL_CACHE.accounts().iter().any(|usage| usage.is_expired())
+                    && let Err(e) = refill_calls_and_renew_plans().await
+                {
+                    info!("Failed to refill calls or reset plan for users:\n {}", e);
+                }
+                if let Err(e) = prune_rotated_keys().await {
+                    warn!("Failed to delete rotated keys: {e}");
+                }
+                if let Err(e) = prune_usage().await {
+                    warn!("Failed to delete old usage rollups: {e}");
+                }
+                if let Err(e) = reload_call_cache().await {
+                    warn!("Failed to reload plans into the call cache: {e}");
+                }
+            }
+        }
     }
+}
 
-    // check callcount
-    if sub_info.calls > sub_info.plan.get_plan_limit() as i64 {
-        Err(RpcAuthErrors::OutOfCredits)?
+/// writes the usage metered since the last flush in one round trip
+pub async fn flush_call_cache() -> Result<(), RpcAuthErrors> {
+    flush_last_used().await?;
+    flush_key_usage().await?;
+
+    let mut emails = vec![];
+    let mut calls = vec![];
+    let mut compute_units = vec![];
+    let mut trace_compute_units = vec![];
+    let accounts: Vec<_> = CALL_CACHE
+        .accounts()
+        .into_iter()
+        .filter_map(|usage| {
+            let metered = usage.take_pending();
+            if metered.is_empty() {
+                return None;
+            }
+            emails.push(usage.email.clone());
+            calls.push(metered.calls);
+            compute_units.push(metered.compute_units);
+            trace_compute_units.push(metered.trace_compute_units);
+            Some((usage, metered))
+        })
+        .collect();
+
+    if accounts.is_empty() {
+        return Ok(());
     }
 
-    tokio::spawn(async move {
-        sqlx::query!(
-            "UPDATE RpcPlans SET calls = calls + 1 WHERE email = $1",
-            sub_info.email.as_str(),
-        )
-        .execute(db_connection)
-        .await
-    });
+    let res = sqlx::query!(
+        r#"
+            UPDATE RpcPlans
+            SET
+                calls = calls + deltas.calls,
+                computeUnits = computeUnits + deltas.compute_units,
+                traceComputeUnits = traceComputeUnits + deltas.trace_compute_units
+            FROM UNNEST($1::text[], $2::int8[], $3::int8[], $4::int8[])
+                AS deltas(email, calls, compute_units, trace_compute_units)
+            WHERE RpcPlans.email = deltas.email
+        "#,
+        &emails,
+        &calls,
+        &compute_units,
+        &trace_compute_units,
+    )
+    .execute(RELATIONAL_DATABASE.get().unwrap())
+    .await;
+
+    if let Err(e) = res {
+        // keep the usage around for the next flush
+        for (usage, metered) in accounts {
+            usage.restore_pending(metered);
+        }
+        Err(e)?
+    }
+
+    Ok(())
+}
+
+/// writes when each key was last used, in one round trip
+async fn flush_last_used() -> Result<(), RpcAuthErrors> {
+    let (prefixes, used): (Vec<String>, Vec<i64>) = CALL_CACHE.take_last_used().into_iter().unzip();
+    if prefixes.is_empty() {
+        return Ok(());
+    }
+
+    let res = sqlx::query!(
+        r#"
+            UPDATE Api
+            SET keyLastUsed = to_timestamp(used.at)
+            FROM UNNEST($1::text[], $2::int8[]) AS used(prefix, at)
+            WHERE Api.keyPrefix = used.prefix
+        "#,
+        &prefixes,
+        &used,
+    )
+    .execute(RELATIONAL_DATABASE.get().unwrap())
+    .await;
+
+    if let Err(e) = res {
+        for (prefix, used) in prefixes.iter().zip(used) {
+            CALL_CACHE.restore_last_used(prefix, used);
+        }
+        Err(e)?
+    }
 
-    Ok(next.run(request).await)
+    Ok(())
+}
+
+/// writes the usage of each lineage metered since the last flush, in one round trip.
+/// It is counted on every key of the lineage, so it survives whichever of them is deleted
+async fn flush_key_usage() -> Result<(), RpcAuthErrors> {
+    let mut lineages = vec![];
+    let mut calls = vec![];
+    let mut compute_units = vec![];
+    let keys: Vec<_> = CALL_CACHE
+        .key_usage()
+        .into_iter()
+        .filter_map(|(lineage, usage)| {
+            let (pending_calls, pending_compute_units) = usage.take_pending();
+            if pending_calls == 0 && pending_compute_units == 0 {
+                return None;
+            }
+            lineages.push(lineage);
+            calls.push(pending_calls);
+            compute_units.push(pending_compute_units);
+            Some(usage)
+        })
+        .collect();
+
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let res = sqlx::query!(
+        r#"
+            UPDATE Api
+            SET
+                keyCalls = keyCalls + deltas.calls,
+                keyComputeUnits = keyComputeUnits + deltas.compute_units
+            FROM UNNEST($1::text[], $2::int8[], $3::int8[])
+                AS deltas(lineage, calls, compute_units)
+            WHERE Api.keyLineage = deltas.lineage
+        "#,
+        &lineages,
+        &calls,
+        &compute_units,
+    )
+    .execute(RELATIONAL_DATABASE.get().unwrap())
+    .await;
+
+    if let Err(e) = res {
+        for ((usage, calls), compute_units) in keys.iter().zip(calls).zip(compute_units) {
+            usage.restore_pending(calls, compute_units);
+        }
+        Err(e)?
+    }
+
+    Ok(())
+}
+
+/// deletes keys whose grace period after a rotation is over
+pub async fn prune_rotated_keys() -> Result<(), RpcAuthErrors> {
+    let pruned = sqlx::query_scalar!(
+        "DELETE FROM Api WHERE keySuccessor IS NOT NULL AND keyExpires <= now() RETURNING keyPrefix"
+    )
+    .fetch_all(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
+    for prefix in pruned {
+        CALL_CACHE.remove(&prefix);
+    }
+    Ok(())
+}
+
+pub struct CachedPlan {
+    keyprefix: String,
+    keysalt: String,
+    keyhash: String,
+    keyexpires: Option<OffsetDateTime>,
+    keychains: Option<Vec<String>>,
+    keymethods: Option<Vec<String>>,
+    keydeniedmethods: Option<Vec<String>>,
+    keyreadonly: bool,
+    keyorigins: Option<Vec<String>>,
+    keyipranges: Option<Vec<String>>,
+    keylineage: String,
+    keycallcap: Option<i64>,
+    keycalls: i64,
+    email: String,
+    compute_units: i64,
+    trace_compute_units: i64,
+    plan: Plan,
+    expires: OffsetDateTime,
+}
+
+/// reloads every api key together with the plan of its account
+pub async fn reload_call_cache() -> Result<(), RpcAuthErrors> {
+    let rows = sqlx::query_as!(
+        CachedPlan,
+        r#"
+            SELECT
+                Api.keyPrefix,
+                Api.keySalt,
+                Api.keyHash,
+                Api.keyExpires,
+                Api.keyChains,
+                Api.keyMethods,
+                Api.keyDeniedMethods,
+                Api.keyReadOnly,
+                Api.keyOrigins,
+                Api.keyIpRanges,
+                Api.keyLineage,
+                Api.keyCallCap,
+                Api.keyCalls,
+                RpcPlans.email,
+                computeUnits AS compute_units,
+                traceComputeUnits AS trace_compute_units,
+                plan as "plan!: Plan",
+                expires
+            FROM
+                Api
+            INNER JOIN
+                RpcPlans
+            ON
+                Api.customerEmail = RpcPlans.email
+        "#
+    )
+    .fetch_all(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
+
+    CALL_CACHE.refresh_entire_cache(
+        rows.into_iter()
+            .map(|row| CachedKeyRow {
+                prefix: row.keyprefix,
+                hash: ApiKeyHash {
+                    salt: row.keysalt,
+                    hash: row.keyhash,
+                },
+                expires: row.keyexpires,
+                scope: KeyScope::from_columns(
+                    row.keychains,
+                    row.keymethods,
+                    row.keydeniedmethods,
+                    row.keyreadonly,
+                    row.keyorigins,
+                    row.keyipranges,
+                ),
+                lineage: row.keylineage,
+                cap: row.keycallcap,
+                calls: row.keycalls,
+                usage: AccountUsage::new(
+                    row.email,
+                    row.plan,
+                    row.expires,
+                    row.compute_units,
+                    row.trace_compute_units,
+                ),
+            })
+            .collect(),
+    );
+
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -107,11 +601,22 @@ pub async fn refill_calls_and_renew_plans() -> Result<(), RpcAuthErrors> {
     .fetch_all(&mut *tx)
     .await?;
 
+    // keys are capped per cycle, they start over with their account
+    sqlx::query!(
+        "UPDATE Api SET keyCalls = 0, keyComputeUnits = 0
+        FROM RpcPlans
+        WHERE Api.customerEmail = RpcPlans.email AND now() >= RpcPlans.expires"
+    )
+    .execute(&mut *tx)
+    .await?;
+
     sqlx::query!(
         "UPDATE 
             RpcPlans 
         SET 
             calls = 0,
+            computeUnits = 0,
+            traceComputeUnits = 0,
             created = CURRENT_TIMESTAMP, 
             expires = CURRENT_TIMESTAMP + INTERVAL '1 months'
         WHERE 
@@ -172,20 +677,95 @@ pub async fn refill_calls_and_renew_plans() -> Result<(), RpcAuthErrors> {
 pub enum RpcAuthErrors {
     #[error("The supplied api key is invalid.")]
     InvalidApiKey,
+    #[error("The supplied api key has expired.")]
+    KeyExpired,
+    #[error("The supplied api key is not allowed to access {0}.")]
+    ChainNotInScope(PoktChains),
+    #[error("The supplied api key is not allowed on this origin.")]
+    OriginNotAllowed,
+    #[error("The supplied api key is not allowed from this address.")]
+    IpNotAllowed,
     #[error(transparent)]
     DatabaseError(#[from] sqlx::Error),
     #[error("You have ran out of credits. Please resubscribe if you love our service!")]
     OutOfCredits,
     #[error("Plan expired. Please resubscribe if you love our service!")]
     PlanExpired,
+    #[error("debug_* and trace_* methods are not included in the {0} plan.")]
+    TracingNotInPlan(Plan),
+    #[error("You have used up the trace quota of your plan.")]
+    OutOfTraceQuota,
+    #[error("The supplied api key has reached its monthly call cap.")]
+    KeyCapReached,
+    #[error("Too many requests. Slow down or upgrade your plan for a higher rate limit.")]
+    RateLimited,
+    #[error(transparent)]
+    InvalidPayload(#[from] RouterErrors),
+}
+
+impl JsonRpcFailure for RpcAuthErrors {
+    fn code(&self) -> i64 {
+        match self {
+            RpcAuthErrors::InvalidApiKey | RpcAuthErrors::KeyExpired => UNAUTHORIZED,
+            RpcAuthErrors::DatabaseError(_) => INTERNAL_ERROR,
+            RpcAuthErrors::OutOfCredits
+            | RpcAuthErrors::PlanExpired
+            | RpcAuthErrors::OutOfTraceQuota
+            | RpcAuthErrors::KeyCapReached
+            | RpcAuthErrors::RateLimited => LIMIT_EXCEEDED,
+            RpcAuthErrors::TracingNotInPlan(_) => METHOD_NOT_SUPPORTED,
+            RpcAuthErrors::ChainNotInScope(_)
+            | RpcAuthErrors::OriginNotAllowed
+            | RpcAuthErrors::IpNotAllowed => UNAUTHORIZED,
+            RpcAuthErrors::InvalidPayload(e) => e.code(),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RpcAuthErrors::InvalidApiKey | RpcAuthErrors::KeyExpired => StatusCode::UNAUTHORIZED,
+            RpcAuthErrors::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RpcAuthErrors::OutOfCredits
+            | RpcAuthErrors::PlanExpired
+            | RpcAuthErrors::OutOfTraceQuota
+            | RpcAuthErrors::KeyCapReached => StatusCode::PAYMENT_REQUIRED,
+            RpcAuthErrors::TracingNotInPlan(_)
+            | RpcAuthErrors::ChainNotInScope(_)
+            | RpcAuthErrors::OriginNotAllowed
+            | RpcAuthErrors::IpNotAllowed => StatusCode::FORBIDDEN,
+            RpcAuthErrors::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            RpcAuthErrors::InvalidPayload(e) => e.status(),
+        }
+    }
 }
 
 impl IntoResponse for RpcAuthErrors {
-    fn into_response(self) -> axum::response::Response {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            self.to_string(),
-        )
-            .into_response()
+    fn into_response(self) -> Response {
+        RpcError::new(self, RequestIds::Unknown).into_response()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::request_api_key;
+    use axum::http::{HeaderMap, HeaderValue, header::AUTHORIZATION};
+    use std::collections::HashMap;
+
+    #[test]
+    fn headers_take_precedence_over_the_path() {
+        let path = HashMap::from([("api_key".to_string(), "dd_path".to_string())]);
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_api_key(&headers, &path), Some("dd_path"));
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer dd_bearer"));
+        assert_eq!(request_api_key(&headers, &path), Some("dd_bearer"));
+
+        headers.insert("x-api-key", HeaderValue::from_static("dd_header"));
+        assert_eq!(
+            request_api_key(&headers, &HashMap::new()),
+            Some("dd_header")
+        );
+
+        assert_eq!(request_api_key(&HeaderMap::new(), &HashMap::new()), None);
     }
 }
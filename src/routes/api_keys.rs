@@ -1,23 +1,91 @@
 use super::types::Claims;
-use crate::{database::types::RELATIONAL_DATABASE};
+use crate::{
+    database::types::RELATIONAL_DATABASE,
+    middleware::{
+        allowlist::{IpRange, OriginPattern},
+        rate_limit::RATE_LIMITER,
+    },
+    routes::relayer::{
+        router::CALL_CACHE,
+        types::{JsonRpcRequest, PoktChains},
+    },
+};
 use axum::{
+    Json,
     extract::{Extension, Path},
     http::StatusCode,
     response::IntoResponse,
 };
 use jwt_simple::claims::JWTClaims;
-use rand::{RngExt, rng};
+use rand::{RngExt, distr::Alphanumeric, rng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    sync::{LazyLock, atomic::Ordering},
+    time::Duration,
+};
 use thiserror::Error;
+use time::OffsetDateTime;
+
+/// Marks our keys in logs and secret scanners
+pub const API_KEY_PREFIX: &str = "dd_";
+/// Leading characters of a key that are stored in plain text, identify the key and are shown to the user
+pub const VISIBLE_PREFIX_LEN: usize = 12;
+pub const MAX_KEY_NAME_LEN: usize = 64;
+/// Keys an account may hold, rotated keys count until they are pruned
+pub const MAX_KEYS_PER_ACCOUNT: i64 = 10;
 
 #[derive(Debug, Default)]
 pub struct KeygenLimit {
     count: Option<i64>,
 }
 
+/// Optional settings of a new key, times are unix timestamps
+#[derive(Deserialize, Debug, Default)]
+pub struct NewKey {
+    #[serde(default)]
+    name: String,
+    expires_at: Option<i64>,
+    #[serde(default)]
+    scope: KeyScope,
+    // monthly calls of the key, None draws from the plan of the account alone
+    call_cap: Option<i64>,
+}
+
+/// The only time the full key is returned, afterwards only its prefix is known
+#[derive(Serialize, Debug)]
+pub struct CreatedKey {
+    key: String,
+    prefix: String,
+    name: String,
+    expires_at: Option<i64>,
+    scope: KeyScope,
+    call_cap: Option<i64>,
+}
+
 pub async fn generate_api_keys(
     Extension(jwt): Extension<JWTClaims<Claims<'static>>>,
+    new_key: Option<Json<NewKey>>,
 ) -> Result<impl IntoResponse, ApiKeyError> {
+    let NewKey {
+        name,
+        expires_at,
+        scope,
+        call_cap,
+    } = new_key.map(|Json(key)| key).unwrap_or_default();
+    validate_name(&name)?;
+    scope.validate()?;
+    validate_cap(call_cap)?;
+    let expires = expires_at
+        .map(OffsetDateTime::from_unix_timestamp)
+        .transpose()
+        .map_err(|_| ApiKeyError::InvalidExpiry)?;
+    if expires.is_some_and(|expires| expires <= OffsetDateTime::now_utc()) {
+        Err(ApiKeyError::InvalidExpiry)?
+    }
+
     let keys = sqlx::query_as!(
         KeygenLimit,
         "SELECT COUNT(*) FROM Api where customerEmail = $1",
@@ -28,25 +96,114 @@ pub async fn generate_api_keys(
     .count
     .unwrap_or_default();
 
-    if keys >= 10i64 {
+    if keys >= MAX_KEYS_PER_ACCOUNT {
         Err(ApiKeyError::RateLimit)?
     }
 
-    let key_string = generate_api_key(64);
+    let chains = scope.chain_names();
+    let origins = scope.origin_patterns();
+    let ip_ranges = scope.ip_range_strings();
+    let key_string = generate_api_key(48);
+    let hash = ApiKeyHash::new(&key_string);
+    let prefix = visible_prefix(&key_string).to_string();
     sqlx::query!(
-        "INSERT INTO Api (customerEmail, apiKey) VALUES ($1, $2)",
+        r#"
+            INSERT INTO Api (
+                customerEmail, keyPrefix, keySalt, keyHash, keyName, keyExpires,
+                keyChains, keyMethods, keyDeniedMethods, keyReadOnly, keyOrigins, keyIpRanges,
+                keyLineage, keyCallCap
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $2, $13)
+        "#,
         jwt.custom.email.as_str(),
-        &key_string
+        &prefix,
+        &hash.salt,
+        &hash.hash,
+        &name,
+        expires,
+        chains.as_deref(),
+        scope.allowed_methods.as_deref(),
+        scope.denied_methods.as_deref(),
+        scope.read_only,
+        origins.as_deref(),
+        ip_ranges.as_deref(),
+        call_cap,
     )
     .execute(RELATIONAL_DATABASE.get().unwrap())
     .await?;
-    println!("{key_string}");
-    Ok((StatusCode::OK, key_string))
+    Ok((
+        StatusCode::OK,
+        Json(CreatedKey {
+            key: key_string,
+            prefix,
+            name,
+            expires_at,
+            scope,
+            call_cap,
+        }),
+    ))
+}
+
+pub struct KeyRow {
+    prefix: String,
+    name: String,
+    created: OffsetDateTime,
+    last_used: Option<OffsetDateTime>,
+    expires: Option<OffsetDateTime>,
+    chains: Option<Vec<String>>,
+    allowed_methods: Option<Vec<String>>,
+    denied_methods: Option<Vec<String>>,
+    read_only: bool,
+    origins: Option<Vec<String>>,
+    ip_ranges: Option<Vec<String>>,
+    successor: Option<String>,
+    call_cap: Option<i64>,
+    calls: i64,
 }
 
+/// A key as listed to its owner, times are unix timestamps
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Keys {
-    apikey: String,
+    prefix: String,
+    name: String,
+    created_at: i64,
+    last_used_at: Option<i64>,
+    expires_at: Option<i64>,
+    scope: KeyScope,
+    // prefix of the key this one was rotated to
+    successor: Option<String>,
+    call_cap: Option<i64>,
+    // calls made with the key and the keys it replaced this cycle
+    calls: i64,
+}
+
+impl From<KeyRow> for Keys {
+    fn from(row: KeyRow) -> Self {
+        // uses that weren't flushed yet are only known to the call cache
+        let cached = CALL_CACHE.count_ref(&row.prefix);
+        let last_used = cached
+            .as_ref()
+            .map(|key| key.last_used.load(Ordering::Relaxed))
+            .filter(|used| *used > 0);
+        Keys {
+            last_used_at: last_used.or(row.last_used.map(OffsetDateTime::unix_timestamp)),
+            calls: cached.map_or(row.calls, |key| key.key_usage.calls.load(Ordering::Relaxed)),
+            call_cap: row.call_cap,
+            prefix: row.prefix,
+            name: row.name,
+            created_at: row.created.unix_timestamp(),
+            expires_at: row.expires.map(OffsetDateTime::unix_timestamp),
+            successor: row.successor,
+            scope: KeyScope::from_columns(
+                row.chains,
+                row.allowed_methods,
+                row.denied_methods,
+                row.read_only,
+                row.origins,
+                row.ip_ranges,
+            ),
+        }
+    }
 }
 
 #[tracing::instrument]
@@ -54,21 +211,274 @@ pub async fn get_all_api_keys(
     Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
 ) -> Result<impl IntoResponse, ApiKeyError> {
     let keys: Vec<Keys> = sqlx::query_as!(
-        Keys,
-        "SELECT apiKey FROM Api where customerEmail = $1",
+        KeyRow,
+        r#"
+            SELECT
+                keyPrefix AS prefix,
+                keyName AS name,
+                keyCreated AS created,
+                keyLastUsed AS last_used,
+                keyExpires AS expires,
+                keyChains AS chains,
+                keyMethods AS allowed_methods,
+                keyDeniedMethods AS denied_methods,
+                keyReadOnly AS read_only,
+                keyOrigins AS origins,
+                keyIpRanges AS ip_ranges,
+                keySuccessor AS successor,
+                keyCallCap AS call_cap,
+                keyCalls AS calls
+            FROM Api
+            WHERE customerEmail = $1
+            ORDER BY keyCreated
+        "#,
         jwt.custom.email.as_str()
     )
     .fetch_all(RELATIONAL_DATABASE.get().unwrap())
-    .await?;
+    .await?
+    .into_iter()
+    .map(Keys::from)
+    .collect();
 
     Ok((StatusCode::OK, serde_json::to_string(&keys)?))
 }
 
-#[tracing::instrument]
-pub async fn delete_key(Path(params): Path<String>) -> Result<impl IntoResponse, ApiKeyError> {
-    sqlx::query_as!(Api, "DELETE FROM Api where apiKey = $1", params)
-        .execute(RELATIONAL_DATABASE.get().unwrap())
-        .await?;
+#[derive(Deserialize, Debug)]
+pub struct RenameKey {
+    name: String,
+}
+
+/// renames a key of the caller
+#[tracing::instrument(skip(jwt))]
+pub async fn rename_key(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Path(prefix): Path<String>,
+    Json(payload): Json<RenameKey>,
+) -> Result<impl IntoResponse, ApiKeyError> {
+    validate_name(&payload.name)?;
+    let renamed = sqlx::query!(
+        "UPDATE Api SET keyName = $1 WHERE keyPrefix = $2 AND customerEmail = $3",
+        payload.name,
+        prefix,
+        jwt.custom.email.as_str()
+    )
+    .execute(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
+    if renamed.rows_affected() == 0 {
+        Err(ApiKeyError::KeyNotFound)?
+    }
+
+    Ok((StatusCode::OK, "Key successfully renamed"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeyCap {
+    call_cap: Option<i64>,
+}
+
+/// caps the monthly calls of a key of the caller, a null cap removes it.
+/// The cap is shared with the keys it was rotated from or to
+#[tracing::instrument(skip(jwt))]
+pub async fn set_key_cap(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Path(prefix): Path<String>,
+    Json(payload): Json<KeyCap>,
+) -> Result<impl IntoResponse, ApiKeyError> {
+    validate_cap(payload.call_cap)?;
+    let lineage = sqlx::query_scalar!(
+        r#"
+            UPDATE Api SET keyCallCap = $1
+            WHERE customerEmail = $3 AND keyLineage = (
+                SELECT keyLineage FROM Api WHERE keyPrefix = $2 AND customerEmail = $3
+            )
+            RETURNING keyLineage
+        "#,
+        payload.call_cap,
+        prefix,
+        jwt.custom.email.as_str()
+    )
+    .fetch_all(RELATIONAL_DATABASE.get().unwrap())
+    .await?
+    .pop()
+    .ok_or(ApiKeyError::KeyNotFound)?;
+    CALL_CACHE.set_lineage_cap(&lineage, payload.call_cap);
+
+    Ok((StatusCode::OK, "Key cap successfully updated"))
+}
+
+/// How long a rotated key keeps working by default
+pub static KEY_ROTATION_GRACE: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        dotenvy::var("KEY_ROTATION_GRACE_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(24 * 60 * 60),
+    )
+});
+
+/// Longest grace period a rotation may ask for
+pub const MAX_ROTATION_GRACE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Rotation {
+    grace_secs: Option<u64>,
+}
+
+/// The successor of a rotated key, returned in full once like a new key
+#[derive(Serialize, Debug)]
+pub struct RotatedKey {
+    key: String,
+    prefix: String,
+    replaces: String,
+    replaced_key_expires_at: i64,
+}
+
+/// Issues a successor with the name, scope, cap, usage and expiry of a key.
+/// The old key keeps working until the grace period ends, both draw from the same limits.
+#[tracing::instrument(skip(jwt, rotation))]
+pub async fn rotate_key(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Path(prefix): Path<String>,
+    rotation: Option<Json<Rotation>>,
+) -> Result<impl IntoResponse, ApiKeyError> {
+    let grace = match rotation.and_then(|Json(rotation)| rotation.grace_secs) {
+        Some(secs) => Duration::from_secs(secs),
+        None => *KEY_ROTATION_GRACE,
+    };
+    if grace > MAX_ROTATION_GRACE {
+        Err(ApiKeyError::InvalidGrace)?
+    }
+
+    let key_string = generate_api_key(48);
+    let hash = ApiKeyHash::new(&key_string);
+    let successor = visible_prefix(&key_string).to_string();
+
+    let mut tx = RELATIONAL_DATABASE.get().unwrap().begin().await?;
+    // the successor counts towards the keys of the account. The account stays locked
+    // so concurrent rotations can't exceed the limit together
+    sqlx::query!(
+        "SELECT email FROM Customers WHERE email = $1 FOR UPDATE",
+        jwt.custom.email.as_str(),
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let keys = sqlx::query_as!(
+        KeygenLimit,
+        "SELECT COUNT(*) FROM Api where customerEmail = $1",
+        jwt.custom.email.as_str()
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .count
+    .unwrap_or_default();
+    if keys >= MAX_KEYS_PER_ACCOUNT {
+        Err(ApiKeyError::RateLimit)?
+    }
+
+    // keys that were rotated already or expired can't be rotated.
+    // The row stays locked so concurrent rotations of the key wait and then find it rotated
+    let current = sqlx::query!(
+        r#"
+            SELECT keyExpires AS expires
+            FROM Api
+            WHERE
+                keyPrefix = $1
+                AND customerEmail = $2
+                AND keySuccessor IS NULL
+                AND (keyExpires IS NULL OR keyExpires > now())
+            FOR UPDATE
+        "#,
+        &prefix,
+        jwt.custom.email.as_str(),
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiKeyError::NotRotatable)?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO Api (
+                customerEmail, keyPrefix, keySalt, keyHash, keyName, keyExpires,
+                keyChains, keyMethods, keyDeniedMethods, keyReadOnly, keyOrigins, keyIpRanges,
+                keyLineage, keyCallCap, keyCalls, keyComputeUnits
+            )
+            SELECT
+                customerEmail, $1, $2, $3, keyName, keyExpires,
+                keyChains, keyMethods, keyDeniedMethods, keyReadOnly, keyOrigins, keyIpRanges,
+                keyLineage, keyCallCap, keyCalls, keyComputeUnits
+            FROM Api
+            WHERE keyPrefix = $4
+        "#,
+        &successor,
+        &hash.salt,
+        &hash.hash,
+        &prefix,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let expires = replaced_key_expiry(current.expires, OffsetDateTime::now_utc(), grace);
+    sqlx::query!(
+        "UPDATE Api SET keySuccessor = $1, keyExpires = $2 WHERE keyPrefix = $3",
+        &successor,
+        expires,
+        &prefix,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    // updated in place, usage of the key that wasn't flushed yet is kept
+    CALL_CACHE.update(&prefix, |key| key.expires = Some(expires));
+
+    Ok((
+        StatusCode::OK,
+        Json(RotatedKey {
+            key: key_string,
+            prefix: successor,
+            replaces: prefix,
+            replaced_key_expires_at: expires.unix_timestamp(),
+        }),
+    ))
+}
+
+/// a rotated key works until its grace period ends, or until it would have expired anyway
+fn replaced_key_expiry(
+    expires: Option<OffsetDateTime>,
+    now: OffsetDateTime,
+    grace: Duration,
+) -> OffsetDateTime {
+    let end_of_grace = now + grace;
+    expires.map_or(end_of_grace, |expires| expires.min(end_of_grace))
+}
+
+/// deletes a key of the caller by its prefix
+#[tracing::instrument(skip(jwt))]
+pub async fn delete_key(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Path(prefix): Path<String>,
+) -> Result<impl IntoResponse, ApiKeyError> {
+    let db = RELATIONAL_DATABASE.get().unwrap();
+    let lineage = sqlx::query_scalar!(
+        "DELETE FROM Api where keyPrefix = $1 AND customerEmail = $2 RETURNING keyLineage",
+        prefix,
+        jwt.custom.email.as_str()
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiKeyError::KeyNotFound)?;
+    CALL_CACHE.remove(&prefix);
+
+    // the rate limit is shared with keys rotated from or to this one
+    let shared = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM Api WHERE keyLineage = $1) AS "shared!""#,
+        lineage
+    )
+    .fetch_one(db)
+    .await?;
+    if !shared {
+        RATE_LIMITER.remove(&lineage);
+    }
 
     Ok((StatusCode::OK, "Key successfully deleted"))
 }
@@ -83,31 +493,306 @@ pub enum ApiKeyError {
     KeyNotFound,
     #[error("You have reached your maximum allocation of API keys.")]
     RateLimit,
+    #[error("Key names are limited to {MAX_KEY_NAME_LEN} characters.")]
+    InvalidName,
+    #[error("Keys must expire in the future.")]
+    InvalidExpiry,
+    #[error("Method patterns must not be empty or match every method.")]
+    InvalidScope,
+    #[error("Call caps must be greater than 0.")]
+    InvalidCap,
+    #[error("Rotated keys can stay valid for at most {} days.", MAX_ROTATION_GRACE.as_secs() / 86400)]
+    InvalidGrace,
+    #[error("Only keys that are active and weren't rotated yet can be rotated.")]
+    NotRotatable,
 }
 
 impl IntoResponse for ApiKeyError {
     fn into_response(self) -> axum::response::Response {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            self.to_string(),
-        )
-            .into_response()
+        let status = match self {
+            ApiKeyError::KeyNotFound => StatusCode::NOT_FOUND,
+            ApiKeyError::InvalidName
+            | ApiKeyError::InvalidExpiry
+            | ApiKeyError::InvalidScope
+            | ApiKeyError::InvalidCap
+            | ApiKeyError::InvalidGrace => StatusCode::BAD_REQUEST,
+            ApiKeyError::NotRotatable => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), ApiKeyError> {
+    if name.chars().count() > MAX_KEY_NAME_LEN {
+        Err(ApiKeyError::InvalidName)?
     }
+    Ok(())
 }
 
+fn validate_cap(call_cap: Option<i64>) -> Result<(), ApiKeyError> {
+    if call_cap.is_some_and(|cap| cap <= 0) {
+        Err(ApiKeyError::InvalidCap)?
+    }
+    Ok(())
+}
+
+/// keys are alphanumeric so they can travel in paths and headers alike
 #[inline]
 pub fn generate_api_key(size: usize) -> String {
-    rng()
-        .random_iter()
-        .take(size)
-        // 32-126 are the printable ASCII characters
-        .map(|e: u8| (e % 94) + 32)
-        .fold(String::with_capacity(size), |mut acc, e| {
-            acc.push(e as char);
-            acc
-        })
+    let mut key = String::with_capacity(API_KEY_PREFIX.len() + size);
+    key.push_str(API_KEY_PREFIX);
+    key.extend(rng().sample_iter(Alphanumeric).take(size).map(char::from));
+    key
+}
+
+pub fn visible_prefix(key: &str) -> &str {
+    match key.char_indices().nth(VISIBLE_PREFIX_LEN) {
+        Some((i, _)) => &key[..i],
+        None => key,
+    }
+}
+
+/// Salted SHA-256 of a key. Keys are long and random, so a fast hash is enough
+/// and keys can be verified on every request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyHash {
+    pub salt: String,
+    pub hash: String,
+}
+
+impl ApiKeyHash {
+    pub fn new(key: &str) -> Self {
+        let salt = hex::encode(rng().random::<[u8; 16]>());
+        let hash = hash_api_key(&salt, key);
+        ApiKeyHash { salt, hash }
+    }
+
+    pub fn verifies(&self, key: &str) -> bool {
+        let hash = hash_api_key(&self.salt, key);
+        // constant time, the hash is compared against attacker controlled input
+        hash.len() == self.hash.len()
+            && hash
+                .bytes()
+                .zip(self.hash.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+// same as encode(sha256(convert_to(keySalt || key, 'UTF8')), 'hex') in the migration
+fn hash_api_key(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(key.as_bytes());
+    hex::encode(hasher.finalize())
 }
 
 // limits for API key generation to avoid abuse
-//
-// maybe scope api key permissions in the future
+
+/// What a key may be used for, keys without a scope are unrestricted.
+/// Method patterns match exactly or by prefix when they end in `*`, e.g. `eth_get*`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyScope {
+    #[serde(default, with = "chain_names")]
+    pub chains: Option<Vec<PoktChains>>,
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_methods: Option<Vec<String>>,
+    // rejects methods that broadcast, for keys shipped in frontends
+    #[serde(default)]
+    pub read_only: bool,
+    // pages and client addresses the key may be used from
+    #[serde(default)]
+    pub origins: Option<Vec<OriginPattern>>,
+    #[serde(default)]
+    pub ip_ranges: Option<Vec<IpRange>>,
+}
+
+impl KeyScope {
+    /// builds the scope from its columns, chains this build doesn't serve are dropped
+    pub fn from_columns(
+        chains: Option<Vec<String>>,
+        allowed_methods: Option<Vec<String>>,
+        denied_methods: Option<Vec<String>>,
+        read_only: bool,
+        origins: Option<Vec<String>>,
+        ip_ranges: Option<Vec<String>>,
+    ) -> Self {
+        KeyScope {
+            chains: parse_all(chains),
+            allowed_methods,
+            denied_methods,
+            read_only,
+            origins: parse_all(origins),
+            ip_ranges: parse_all(ip_ranges),
+        }
+    }
+
+    /// chain names as stored in Postgres
+    pub fn chain_names(&self) -> Option<Vec<String>> {
+        to_strings(&self.chains)
+    }
+
+    pub fn origin_patterns(&self) -> Option<Vec<String>> {
+        to_strings(&self.origins)
+    }
+
+    pub fn ip_range_strings(&self) -> Option<Vec<String>> {
+        to_strings(&self.ip_ranges)
+    }
+
+    /// keys limited to origins can only be used by pages on them
+    pub fn allows_origin(&self, host: Option<&str>) -> bool {
+        self.origins.as_ref().is_none_or(|origins| {
+            host.is_some_and(|host| origins.iter().any(|origin| origin.matches(host)))
+        })
+    }
+
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        self.ip_ranges
+            .as_ref()
+            .is_none_or(|ranges| ip.is_some_and(|ip| ranges.iter().any(|range| range.contains(ip))))
+    }
+
+    pub fn allows_chain(&self, chain: PoktChains) -> bool {
+        self.chains
+            .as_ref()
+            .is_none_or(|chains| chains.contains(&chain))
+    }
+
+    pub fn allows(&self, request: &JsonRpcRequest) -> bool {
+        let matches = |patterns: &Vec<String>| {
+            patterns
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => request.method.starts_with(prefix),
+                    None => request.method == *pattern,
+                })
+        };
+        (!self.read_only || !request.is_write())
+            && !self.denied_methods.as_ref().is_some_and(matches)
+            && self.allowed_methods.as_ref().is_none_or(matches)
+    }
+
+    fn validate(&self) -> Result<(), ApiKeyError> {
+        let patterns = self.allowed_methods.iter().chain(&self.denied_methods);
+        if patterns
+            .flatten()
+            .any(|pattern| pattern.is_empty() || pattern == "*")
+        {
+            Err(ApiKeyError::InvalidScope)?
+        }
+        Ok(())
+    }
+}
+
+fn parse_all<T: FromStr>(values: Option<Vec<String>>) -> Option<Vec<T>> {
+    values.map(|values| values.iter().filter_map(|v| v.parse().ok()).collect())
+}
+
+fn to_strings<T: ToString>(values: &Option<Vec<T>>) -> Option<Vec<String>> {
+    values
+        .as_ref()
+        .map(|values| values.iter().map(ToString::to_string).collect())
+}
+
+// chains are named like in the `/rpc/{chain}` path
+mod chain_names {
+    use crate::routes::relayer::types::PoktChains;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(
+        chains: &Option<Vec<PoktChains>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match chains {
+            Some(chains) => serializer.collect_seq(chains.iter().map(ToString::to_string)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<PoktChains>>, D::Error> {
+        Option::<Vec<String>>::deserialize(deserializer)?
+            .map(|chains| {
+                chains
+                    .iter()
+                    .map(|chain| {
+                        chain
+                            .parse()
+                            .map_err(|_| D::Error::custom(format!("unknown chain {chain}")))
+                    })
+                    .collect()
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{
+        API_KEY_PREFIX, ApiKeyHash, KeyScope, VISIBLE_PREFIX_LEN, generate_api_key,
+        replaced_key_expiry, visible_prefix,
+    };
+    use crate::{
+        database::types::Plan,
+        routes::relayer::router::{AccountUsage, CALL_CACHE, CachedKeyRow},
+    };
+    use std::time::Duration;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn hashes_and_verifies_keys() {
+        let key = generate_api_key(48);
+        assert!(key.starts_with(API_KEY_PREFIX));
+        assert_eq!(visible_prefix(&key).len(), VISIBLE_PREFIX_LEN);
+
+        let hash = ApiKeyHash::new(&key);
+        assert!(hash.verifies(&key));
+        assert!(!hash.verifies(&generate_api_key(48)));
+        // every key gets its own salt
+        assert_ne!(ApiKeyHash::new(&key).hash, hash.hash);
+    }
+
+    #[test]
+    fn rotated_keys_expire_after_the_grace_period() {
+        let now = OffsetDateTime::now_utc();
+        let grace = Duration::from_secs(3600);
+        assert_eq!(replaced_key_expiry(None, now, grace), now + grace);
+        // a key expiring sooner keeps its expiry
+        let soon = now + Duration::from_secs(60);
+        assert_eq!(replaced_key_expiry(Some(soon), now, grace), soon);
+        let later = now + Duration::from_secs(7200);
+        assert_eq!(replaced_key_expiry(Some(later), now, grace), now + grace);
+        assert_eq!(replaced_key_expiry(Some(later), now, Duration::ZERO), now);
+
+        // the cached predecessor is refused once its grace period is over, the successor is not
+        let rotated = now - Duration::from_secs(7200);
+        let row = |prefix: &str, expires| CachedKeyRow {
+            prefix: prefix.to_string(),
+            hash: ApiKeyHash::new(prefix),
+            expires,
+            scope: KeyScope::default(),
+            lineage: "grace-old".to_string(),
+            cap: None,
+            calls: 0,
+            usage: AccountUsage::new("grace@aol.com".to_string(), Plan::Free, later, 0, 0),
+        };
+        CALL_CACHE.insert(row(
+            "grace-old",
+            Some(replaced_key_expiry(None, rotated, grace)),
+        ));
+        CALL_CACHE.insert(row("grace-new", None));
+        assert!(CALL_CACHE.count_ref("grace-old").unwrap().is_expired());
+        assert!(!CALL_CACHE.count_ref("grace-new").unwrap().is_expired());
+
+        CALL_CACHE.insert(row(
+            "grace-recent",
+            Some(replaced_key_expiry(None, now, grace)),
+        ));
+        assert!(!CALL_CACHE.count_ref("grace-recent").unwrap().is_expired());
+    }
+}
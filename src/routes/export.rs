@@ -0,0 +1,377 @@
+use super::{types::Claims, usage::Granularity};
+use crate::database::types::RELATIONAL_DATABASE;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, Query},
+    http::{
+        HeaderValue, StatusCode,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
+};
+use futures_util::{StreamExt, stream::BoxStream};
+use jwt_simple::claims::JWTClaims;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+/// Most rows a single page of an export may hold
+pub const MAX_EXPORT_ROWS: i64 = 100_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// Range and shape of an export, times are unix timestamps and `to` is exclusive.
+/// The whole range is streamed unless a page is asked for.
+#[derive(Deserialize, Debug)]
+pub struct ExportQuery {
+    from: i64,
+    to: i64,
+    #[serde(default)]
+    format: ExportFormat,
+    // usage only, daily rollups unless asked otherwise
+    granularity: Option<Granularity>,
+    key: Option<String>,
+    chain: Option<String>,
+    // pages start at 0, `page` alone is not enough
+    page: Option<u32>,
+    per_page: Option<i64>,
+}
+
+impl ExportQuery {
+    fn range(&self) -> Result<(OffsetDateTime, OffsetDateTime), ExportError> {
+        let timestamp =
+            |unix| OffsetDateTime::from_unix_timestamp(unix).map_err(|_| ExportError::InvalidRange);
+        let (from, to) = (timestamp(self.from)?, timestamp(self.to)?);
+        if from >= to {
+            Err(ExportError::InvalidRange)?
+        }
+        Ok((from, to))
+    }
+
+    /// LIMIT and OFFSET of the query, no limit reads the whole range
+    fn window(&self) -> Result<(Option<i64>, i64), ExportError> {
+        match (self.page, self.per_page) {
+            (None, None) => Ok((None, 0)),
+            (page, Some(per_page)) if (1..=MAX_EXPORT_ROWS).contains(&per_page) => {
+                Ok((Some(per_page), i64::from(page.unwrap_or(0)) * per_page))
+            }
+            _ => Err(ExportError::InvalidPage),
+        }
+    }
+
+    fn file_name(&self, dataset: &str) -> String {
+        format!(
+            "{dataset}-{}-{}.{}",
+            self.from,
+            self.to,
+            self.format.extension()
+        )
+    }
+}
+
+/// A row of an export, written as a line of CSV or JSON
+pub trait ExportRow: Serialize {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+
+    fn encode(&self, format: ExportFormat) -> Result<Bytes, serde_json::Error> {
+        let mut line = match format {
+            ExportFormat::Csv => csv_line(self.fields()).into_bytes(),
+            ExportFormat::Jsonl => serde_json::to_vec(self)?,
+        };
+        line.push(b'\n');
+        Ok(line.into())
+    }
+}
+
+/// quotes fields with separators, quotes or line breaks in them
+fn csv_line(fields: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    fields
+        .into_iter()
+        .map(|field| {
+            let field = field.as_ref();
+            match field.contains([',', '"', '\n', '\r']) {
+                true => format!("\"{}\"", field.replace('"', "\"\"")),
+                false => field.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Calls of one key, chain, method and outcome in a bucket
+#[derive(Serialize, Debug)]
+pub struct UsageRecord {
+    bucket: String,
+    key: String,
+    chain: String,
+    method: String,
+    status: String,
+    calls: i64,
+    compute_units: i64,
+}
+
+impl ExportRow for UsageRecord {
+    const HEADER: &'static [&'static str] = &[
+        "bucket",
+        "key",
+        "chain",
+        "method",
+        "status",
+        "calls",
+        "compute_units",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.bucket.clone(),
+            self.key.clone(),
+            self.chain.clone(),
+            self.method.clone(),
+            self.status.clone(),
+            self.calls.to_string(),
+            self.compute_units.to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct PaymentRecord {
+    date: String,
+    transaction_hash: String,
+    chain: String,
+    asset: String,
+    amount: String,
+    decimals: i32,
+    usd_value: i64,
+}
+
+impl ExportRow for PaymentRecord {
+    const HEADER: &'static [&'static str] = &[
+        "date",
+        "transaction_hash",
+        "chain",
+        "asset",
+        "amount",
+        "decimals",
+        "usd_value",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.date.clone(),
+            self.transaction_hash.clone(),
+            self.chain.clone(),
+            self.asset.clone(),
+            self.amount.clone(),
+            self.decimals.to_string(),
+            self.usd_value.to_string(),
+        ]
+    }
+}
+
+/// streams rows into the response as they are read from Postgres
+fn stream_export<T: ExportRow + Send + 'static>(
+    query: &ExportQuery,
+    dataset: &str,
+    rows: BoxStream<'static, Result<T, sqlx::Error>>,
+) -> Response {
+    let format = query.format;
+    let header = match format {
+        ExportFormat::Csv => Some(Ok(Bytes::from(csv_line(T::HEADER) + "\n"))),
+        ExportFormat::Jsonl => None,
+    };
+    // a failure halfway through aborts the response instead of ending the file early
+    let lines = rows.map(move |row| -> Result<Bytes, ExportError> { Ok(row?.encode(format)?) });
+    let body = Body::from_stream(futures_util::stream::iter(header).chain(lines));
+
+    let mut res = (StatusCode::OK, body).into_response();
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}\"",
+        query.file_name(dataset)
+    )) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    res
+}
+
+/// usage of the caller's keys in a range, rolled up per key, chain, method and outcome
+#[tracing::instrument(skip(jwt))]
+pub async fn export_usage(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse, ExportError> {
+    let (from, to) = query.range()?;
+    let (limit, offset) = query.window()?;
+    let granularity = query.granularity.unwrap_or(Granularity::Day);
+    let rows = sqlx::query_as!(
+        UsageRecord,
+        r#"
+            SELECT
+                to_char(bucket AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') AS "bucket!",
+                keyPrefix AS key,
+                chain,
+                method,
+                status,
+                calls,
+                computeUnits AS compute_units
+            FROM UsageRollups
+            WHERE
+                customerEmail = $1
+                AND granularity = $2
+                AND bucket >= $3
+                AND bucket < $4
+                AND ($5::text IS NULL OR keyPrefix = $5)
+                AND ($6::text IS NULL OR chain = $6)
+            ORDER BY bucket, keyPrefix, chain, method, status
+            LIMIT $7
+            OFFSET $8
+        "#,
+        jwt.custom.email.as_str(),
+        granularity.as_str(),
+        from,
+        to,
+        query.key,
+        query.chain,
+        limit,
+        offset,
+    )
+    .fetch(RELATIONAL_DATABASE.get().unwrap());
+
+    Ok(stream_export(&query, "usage", rows))
+}
+
+/// payments of the caller in a range
+#[tracing::instrument(skip(jwt))]
+pub async fn export_payments(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse, ExportError> {
+    let (from, to) = query.range()?;
+    let (limit, offset) = query.window()?;
+    let rows = sqlx::query_as!(
+        PaymentRecord,
+        r#"
+            SELECT
+                to_char(date AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') AS "date!",
+                transactionHash AS transaction_hash,
+                chain::text AS "chain!",
+                asset::text AS "asset!",
+                amount,
+                decimals,
+                usdValue AS usd_value
+            FROM Payments
+            WHERE customerEmail = $1 AND date >= $2 AND date < $3
+            ORDER BY date, transactionHash
+            LIMIT $4
+            OFFSET $5
+        "#,
+        jwt.custom.email.as_str(),
+        from,
+        to,
+        limit,
+        offset,
+    )
+    .fetch(RELATIONAL_DATABASE.get().unwrap());
+
+    Ok(stream_export(&query, "payments", rows))
+}
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("Ranges must end after they start.")]
+    InvalidRange,
+    #[error("Pages need a per_page of 1 to {MAX_EXPORT_ROWS} rows.")]
+    InvalidPage,
+}
+
+impl IntoResponse for ExportError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ExportError::InvalidRange | ExportError::InvalidPage => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{ExportFormat, ExportQuery, ExportRow, UsageRecord, csv_line};
+    use axum::extract::Query;
+
+    #[test]
+    fn encodes_csv_and_json_lines() {
+        assert_eq!(
+            csv_line(["a", "b,c", "say \"hi\""]),
+            "a,\"b,c\",\"say \"\"hi\"\"\""
+        );
+
+        let record = UsageRecord {
+            bucket: "2024-01-01T00:00:00Z".to_string(),
+            key: "dd_abcdefghi".to_string(),
+            chain: "base".to_string(),
+            method: "eth_call".to_string(),
+            status: "success".to_string(),
+            calls: 3,
+            compute_units: 6,
+        };
+        assert_eq!(
+            record.encode(ExportFormat::Csv).unwrap(),
+            "2024-01-01T00:00:00Z,dd_abcdefghi,base,eth_call,success,3,6\n"
+        );
+        let line = record.encode(ExportFormat::Jsonl).unwrap();
+        assert!(line.ends_with(b"\n"));
+        let json: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(json["compute_units"], 6);
+        assert_eq!(UsageRecord::HEADER.len(), record.fields().len());
+    }
+
+    #[test]
+    fn exports_the_whole_range_by_default() {
+        let query = |params: &str| {
+            let uri = format!("/export?from=0&to=10{params}").parse().unwrap();
+            Query::<ExportQuery>::try_from_uri(&uri).unwrap().0
+        };
+        assert_eq!(query("").window().unwrap(), (None, 0));
+        assert_eq!(query("&per_page=50").window().unwrap(), (Some(50), 0));
+        assert_eq!(
+            query("&page=2&per_page=50").window().unwrap(),
+            (Some(50), 100)
+        );
+        assert!(query("&page=2").window().is_err());
+        assert!(query("&per_page=0").window().is_err());
+    }
+}
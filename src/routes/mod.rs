@@ -1,5 +1,6 @@
 pub mod activate;
 pub mod api_keys;
+pub mod export;
 pub mod login;
 pub mod payment;
 pub mod recovery;
@@ -8,3 +9,4 @@ pub mod relayer;
 pub mod siwe;
 pub mod token_queries;
 pub mod types;
+pub mod usage;
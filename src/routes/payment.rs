@@ -2,6 +2,7 @@ use super::types::{Claims, EmailAddress};
 use crate::database::types::{Asset, Chain, Payments, Plan, RELATIONAL_DATABASE};
 #[cfg(test)]
 use crate::eth_rpc::types::TESTING_ENDPOINT;
+use crate::routes::relayer::router::CALL_CACHE;
 use alloy::consensus::Transaction;
 use alloy::eips::BlockId;
 use alloy::primitives::ruint::ParseError;
@@ -139,33 +140,87 @@ pub struct Downgrade {
     plan: Plan,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct Pagination {
     page: usize,
     per_page: usize,
 }
 
+impl Pagination {
+    /// rows before the page, pages start at 0
+    pub fn offset(&self) -> i64 {
+        i64::try_from(self.page.saturating_mul(self.per_page)).unwrap_or(i64::MAX)
+    }
+
+    pub fn limit(&self) -> i64 {
+        i64::try_from(self.per_page).unwrap_or(i64::MAX)
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserBalances {
     calls: i64,
+    // tracing included
+    compute_units: i64,
+    // the part of compute_units drawn from the trace quota of the plan
+    trace_compute_units: i64,
+    trace_limit: i64,
     balance: i64,
 }
 
+/// usage of one api key this cycle
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    prefix: String,
+    name: String,
+    calls: i64,
+    compute_units: i64,
+    call_cap: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallsAndBalance {
+    #[serde(flatten)]
+    totals: UserBalances,
+    // usage of the account broken down by key, both as of the last flush
+    keys: Vec<KeyUsage>,
+}
+
 pub async fn get_calls_and_balance<'a>(
     Extension(jwt): Extension<JWTClaims<Claims<'a>>>,
 ) -> Result<impl IntoResponse, PaymentError> {
-    let res = sqlx::query_as!(
-        UserBalances,
-        "SELECT calls, balance 
-        FROM Customers, RpcPlans 
-        where Customers.email = $1 
-        AND 
-        RpcPlans.email = $1",
+    let res = sqlx::query!(
+        r#"SELECT calls, computeUnits AS compute_units, traceComputeUnits AS trace_compute_units,
+            plan as "plan!: Plan", balance
+        FROM Customers, RpcPlans
+        where Customers.email = $1
+        AND
+        RpcPlans.email = $1"#,
         jwt.custom.email.as_str()
     )
     .fetch_one(RELATIONAL_DATABASE.get().unwrap())
     .await?;
+    let res = UserBalances {
+        calls: res.calls,
+        compute_units: res.compute_units,
+        trace_compute_units: res.trace_compute_units,
+        trace_limit: res.plan.get_trace_limit() as i64,
+        balance: res.balance,
+    };
+
+    let keys = sqlx::query_as!(
+        KeyUsage,
+        "SELECT keyPrefix AS prefix, keyName AS name, keyCalls AS calls,
+            keyComputeUnits AS compute_units, keyCallCap AS call_cap
+        FROM Api
+        WHERE customerEmail = $1
+        ORDER BY keyCreated",
+        jwt.custom.email.as_str()
+    )
+    .fetch_all(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
 
+    let res = CallsAndBalance { totals: res, keys };
     Ok((StatusCode::OK, serde_json::to_string(&res)?).into_response())
 }
 
@@ -339,16 +394,24 @@ pub async fn upgrade(
     }
 
     // plan gets written to DB row
-    // calls is set to 0 because we prorated the total number of calls made by the user previously
+    // calls and compute units are set to 0 because we prorated the usage of the user previously
     sqlx::query!(
-        r#"UPDATE RpcPlans SET plan = $1, calls = 0, downgradeto=NULL WHERE email = $2"#,
+        r#"UPDATE RpcPlans SET plan = $1, calls = 0, computeUnits = 0, traceComputeUnits = 0, downgradeto=NULL WHERE email = $2"#,
         payload.plan as Plan,
         jwt.custom.email.as_str(),
     )
     .execute(&mut *tx)
     .await?;
+    sqlx::query!(
+        "UPDATE Api SET keyCalls = 0, keyComputeUnits = 0 WHERE customerEmail = $1",
+        jwt.custom.email.as_str(),
+    )
+    .execute(&mut *tx)
+    .await?;
 
     tx.commit().await?;
+    // metering continues on the new plan right away, not after the next reload
+    CALL_CACHE.reset_account(jwt.custom.email.as_str(), payload.plan);
 
     Ok((StatusCode::OK, "Successfully applied payment").into_response())
 }
@@ -0,0 +1,286 @@
+use super::{
+    coalesce::coalesce_key,
+    types::{JsonRpcPayload, JsonRpcRequest, PoktChains},
+};
+use axum::body::Bytes;
+use serde_json::{Value, json};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{LazyLock, Mutex},
+};
+
+/// Upper bound of the memory held by cached results
+pub static RESPONSE_CACHE_BYTES: LazyLock<usize> = LazyLock::new(|| {
+    dotenvy::var("RESPONSE_CACHE_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(64 * 1024 * 1024)
+});
+
+pub static RESPONSE_CACHE: LazyLock<ResponseCache> =
+    LazyLock::new(|| ResponseCache::new(*RESPONSE_CACHE_BYTES));
+
+/// When the result of a call can no longer change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Immutability {
+    // constant for the lifetime of the chain
+    Always,
+    // pinned to a block hash
+    Pinned,
+    // pinned to a block number, valid once that block is finalized
+    AtBlock(u64),
+    // the block the result belongs to is only known from the result itself
+    ByResult,
+}
+
+/// Cache of deterministic JSON-RPC results, keyed by chain, method and normalized params.
+/// Only results that are final are stored, so entries never need to be invalidated.
+/// Once full, the oldest entries are evicted first.
+#[derive(Debug)]
+pub struct ResponseCache {
+    inner: Mutex<CacheInner>,
+    max_bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<String, Bytes>,
+    order: VecDeque<String>,
+    bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize) -> Self {
+        ResponseCache {
+            inner: Mutex::new(CacheInner::default()),
+            max_bytes,
+        }
+    }
+
+    /// answers the payload from the cache if every call in it was cached
+    pub fn lookup(&self, chain: PoktChains, payload: &JsonRpcPayload) -> Option<Bytes> {
+        let inner = self.inner.lock().unwrap();
+        let mut responses = vec![];
+        for request in payload.requests() {
+            immutability(request)?;
+            let result = inner.entries.get(&coalesce_key(chain, request))?;
+            // notifications aren't answered
+            if request.id.is_none() {
+                continue;
+            }
+            let result: Value = serde_json::from_slice(result).ok()?;
+            responses.push(json!({"jsonrpc": "2.0", "id": request.id, "result": result}));
+        }
+        drop(inner);
+
+        let body = match (payload, responses.as_slice()) {
+            (_, []) => Ok(vec![]),
+            (JsonRpcPayload::Batch(_), _) => serde_json::to_vec(&responses),
+            (JsonRpcPayload::Single(_), [response, ..]) => serde_json::to_vec(response),
+        };
+        body.ok().map(Bytes::from)
+    }
+
+    /// whether any call of the payload could be stored once answered
+    pub fn is_cacheable(&self, payload: &JsonRpcPayload) -> bool {
+        payload
+            .requests()
+            .iter()
+            .any(|request| immutability(request).is_some())
+    }
+
+    /// stores every final result of an upstream response
+    pub fn store(&self, chain: PoktChains, payload: &JsonRpcPayload, response: &[u8]) {
+        let Ok(response) = serde_json::from_slice::<Value>(response) else {
+            return;
+        };
+        let responses = match response {
+            Value::Array(responses) => responses,
+            response => vec![response],
+        };
+
+        for request in payload.requests() {
+            let Some(immutability) = immutability(request) else {
+                continue;
+            };
+            // batch responses may come back in any order
+            let Some(result) = responses
+                .iter()
+                .find(|response| response.get("id") == request.id.as_ref())
+                .and_then(|response| response.get("result"))
+            else {
+                continue;
+            };
+            if result.is_null() || !is_final(chain, immutability, result) {
+                continue;
+            }
+            if let Ok(result) = serde_json::to_vec(result) {
+                self.insert(coalesce_key(chain, request), Bytes::from(result));
+            }
+        }
+    }
+
+    fn insert(&self, key: String, result: Bytes) {
+        let size = key.len() + result.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.contains_key(&key) {
+            return;
+        }
+        while inner.bytes + size > self.max_bytes {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.bytes -= oldest.len() + evicted.len();
+            }
+        }
+        inner.bytes += size;
+        inner.order.push_back(key.clone());
+        inner.entries.insert(key, result);
+    }
+}
+
+fn is_final(chain: PoktChains, immutability: Immutability, result: &Value) -> bool {
+    let number = match immutability {
+        Immutability::Always | Immutability::Pinned => return true,
+        Immutability::AtBlock(number) => Some(number),
+        Immutability::ByResult => result
+            .get("blockNumber")
+            .or_else(|| result.get("number"))
+            .and_then(Value::as_str)
+            .and_then(parse_quantity),
+    };
+    matches!((number, chain.finalized_height()), (Some(number), Some(finalized)) if number <= finalized)
+}
+
+/// which calls are deterministic and what their result is pinned to
+pub fn immutability(request: &JsonRpcRequest) -> Option<Immutability> {
+    let params = request.params.as_ref();
+    let param = |i: usize| params.and_then(|params| params.get(i));
+    match request.method.as_str() {
+        "eth_chainId" | "net_version" => Some(Immutability::Always),
+        "eth_getBlockByHash"
+        | "eth_getTransactionByHash"
+        | "eth_getTransactionReceipt"
+        | "eth_getTransactionByBlockHashAndIndex" => Some(Immutability::ByResult),
+        "eth_getBlockByNumber"
+        | "eth_getBlockTransactionCountByNumber"
+        | "eth_getTransactionByBlockNumberAndIndex"
+        | "eth_getBlockReceipts" => block_param(param(0)?),
+        "eth_call" | "eth_getBalance" | "eth_getCode" | "eth_getTransactionCount" => {
+            block_param(param(1)?)
+        }
+        "eth_getStorageAt" => block_param(param(2)?),
+        _ => None,
+    }
+}
+
+/// block numbers, block hashes and EIP-1898 block objects. Tags such as `latest` are never final.
+fn block_param(param: &Value) -> Option<Immutability> {
+    match param {
+        Value::String(s) if is_hash(s) => Some(Immutability::Pinned),
+        Value::String(s) => parse_quantity(s).map(Immutability::AtBlock),
+        Value::Object(block) => match (block.get("blockHash"), block.get("blockNumber")) {
+            (Some(Value::String(hash)), _) if is_hash(hash) => Some(Immutability::Pinned),
+            (_, Some(Value::String(number))) => parse_quantity(number).map(Immutability::AtBlock),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_hash(s: &str) -> bool {
+    s.strip_prefix("0x")
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+fn parse_quantity(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{Immutability, ResponseCache, immutability};
+    use crate::routes::relayer::{router::parse_payload, types::PoktChains};
+    use serde_json::{Value, json};
+
+    #[test]
+    fn classifies_calls() {
+        let payload = parse_payload(
+            br#"[
+                {"jsonrpc":"2.0","method":"eth_chainId","id":1},
+                {"jsonrpc":"2.0","method":"eth_getBlockByNumber","params":["0x10",false],"id":2},
+                {"jsonrpc":"2.0","method":"eth_getBlockByNumber","params":["latest",false],"id":3},
+                {"jsonrpc":"2.0","method":"eth_call","params":[{},{"blockHash":"0x1111111111111111111111111111111111111111111111111111111111111111"}],"id":4},
+                {"jsonrpc":"2.0","method":"eth_blockNumber","id":5}
+            ]"#,
+        )
+        .unwrap();
+        let classes: Vec<_> = payload.requests().iter().map(immutability).collect();
+        assert_eq!(
+            classes,
+            [
+                Some(Immutability::Always),
+                Some(Immutability::AtBlock(16)),
+                None,
+                Some(Immutability::Pinned),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn serves_cached_results_with_the_callers_id() {
+        let cache = ResponseCache::new(1024);
+        let chain = PoktChains::Anvil;
+        let first = parse_payload(br#"{"jsonrpc":"2.0","method":"eth_chainId","id":1}"#).unwrap();
+        assert!(cache.lookup(chain, &first).is_none());
+        cache.store(
+            chain,
+            &first,
+            br#"{"jsonrpc":"2.0","id":1,"result":"0x7a69"}"#,
+        );
+
+        let second =
+            parse_payload(br#"[{"jsonrpc":"2.0","method":"eth_chainId","id":"a"}]"#).unwrap();
+        let cached: Value = serde_json::from_slice(&cache.lookup(chain, &second).unwrap()).unwrap();
+        assert_eq!(
+            cached,
+            json!([{"jsonrpc": "2.0", "id": "a", "result": "0x7a69"}])
+        );
+
+        let notifications = parse_payload(
+            br#"[{"jsonrpc":"2.0","method":"eth_chainId","id":2},{"jsonrpc":"2.0","method":"eth_chainId"}]"#,
+        )
+        .unwrap();
+        let cached: Value =
+            serde_json::from_slice(&cache.lookup(chain, &notifications).unwrap()).unwrap();
+        assert_eq!(
+            cached,
+            json!([{"jsonrpc": "2.0", "id": 2, "result": "0x7a69"}])
+        );
+        let notification = parse_payload(br#"{"jsonrpc":"2.0","method":"eth_chainId"}"#).unwrap();
+        assert!(cache.lookup(chain, &notification).unwrap().is_empty());
+    }
+
+    #[test]
+    fn evicts_oldest_entries() {
+        let cache = ResponseCache::new(64);
+        let chain = PoktChains::Anvil;
+        let chain_id =
+            parse_payload(br#"{"jsonrpc":"2.0","method":"eth_chainId","id":1}"#).unwrap();
+        let version = parse_payload(br#"{"jsonrpc":"2.0","method":"net_version","id":1}"#).unwrap();
+        cache.store(chain, &chain_id, br#"{"id":1,"result":"0x7a69"}"#);
+        cache.store(
+            chain,
+            &version,
+            br#"{"id":1,"result":"31337313373133731337"}"#,
+        );
+        assert!(cache.lookup(chain, &chain_id).is_none());
+        assert!(cache.lookup(chain, &version).is_some());
+    }
+}
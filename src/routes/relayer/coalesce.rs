@@ -0,0 +1,210 @@
+use super::{
+    policy::ChainFamily,
+    types::{JsonRpcRequest, PoktChains, RelayErrors},
+};
+use axum::body::Bytes;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{LazyLock, Mutex},
+};
+use tokio::sync::broadcast;
+
+pub static IN_FLIGHT: LazyLock<InFlight> = LazyLock::new(InFlight::default);
+
+/// Identical calls relayed at the same time share a single upstream request.
+/// The first caller relays, everyone arriving before it finishes waits for its response.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    calls: Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+}
+
+impl InFlight {
+    /// runs `relay` unless an identical call is already in flight, in which case its response
+    /// is shared. Waiters relay on their own if the leading call fails or is cancelled.
+    pub async fn run<F>(&self, key: String, relay: F) -> Result<Bytes, RelayErrors>
+    where
+        F: Future<Output = Result<Bytes, RelayErrors>>,
+    {
+        let waiting = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(leader) => Some(leader.subscribe()),
+                None => {
+                    let (tx, _rx) = broadcast::channel(1);
+                    calls.insert(key.clone(), tx);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut rx) = waiting {
+            return match rx.recv().await {
+                Ok(response) => Ok(response),
+                Err(_) => relay.await,
+            };
+        }
+
+        let lead = Lead { calls: self, key };
+        let res = relay.await;
+        if let Ok(response) = &res {
+            lead.share(response.clone());
+        }
+        res
+    }
+}
+
+// removes the call once the leader is done, dropping the sender wakes waiters up on failure
+struct Lead<'a> {
+    calls: &'a InFlight,
+    key: String,
+}
+
+impl Lead<'_> {
+    fn share(&self, response: Bytes) {
+        if let Some(tx) = self.calls.calls.lock().unwrap().remove(&self.key) {
+            let _ = tx.send(response);
+        }
+    }
+}
+
+impl Drop for Lead<'_> {
+    fn drop(&mut self) {
+        self.calls.calls.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl JsonRpcRequest {
+    /// reads whose answer can be shared with every identical call relayed at the same time.
+    /// Filters are excluded since every caller must get its own filter or its own changes.
+    pub fn is_coalescable(&self) -> bool {
+        self.id.is_some()
+            && !self.is_write()
+            && !matches!(
+                self.method.as_str(),
+                "eth_newFilter"
+                    | "eth_newBlockFilter"
+                    | "eth_newPendingTransactionFilter"
+                    | "eth_getFilterChanges"
+                    | "eth_uninstallFilter"
+            )
+    }
+}
+
+/// Calls share a relay or a cached result only when their params are identical. EVM hex is
+/// case insensitive, anything else (e.g. base58 on Solana) is compared as sent. Object keys
+/// serialize sorted since serde_json is built without its `preserve_order` feature.
+pub fn coalesce_key(chain: PoktChains, request: &JsonRpcRequest) -> String {
+    let mut params = request.params.clone().unwrap_or(Value::Null);
+    if chain.family() == ChainFamily::Evm {
+        params = fold_hex(params);
+    }
+    format!("{chain}:{}:{params}", request.method)
+}
+
+fn fold_hex(value: Value) -> Value {
+    match value {
+        Value::String(s) if s.starts_with("0x") => Value::String(s.to_lowercase()),
+        Value::Array(values) => Value::Array(values.into_iter().map(fold_hex).collect()),
+        Value::Object(map) => {
+            Value::Object(map.into_iter().map(|(k, v)| (k, fold_hex(v))).collect())
+        }
+        value => value,
+    }
+}
+
+/// hands a shared response to a caller under its own JSON-RPC id
+pub fn with_id(response: &[u8], id: Option<&Value>) -> Result<Bytes, RelayErrors> {
+    let mut response: Value = serde_json::from_slice(response)?;
+    if let Some(object) = response.as_object_mut() {
+        object.insert("id".to_string(), id.cloned().unwrap_or(Value::Null));
+    }
+    Ok(Bytes::from(serde_json::to_vec(&response)?))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{InFlight, coalesce_key, with_id};
+    use crate::routes::relayer::{router::parse_payload, types::JsonRpcPayload, types::PoktChains};
+    use axum::body::Bytes;
+    use serde_json::{Value, json};
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU32, Ordering},
+        },
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn identical_calls_share_one_relay() {
+        let in_flight = Arc::new(InFlight::default());
+        let relays = Arc::new(AtomicU32::new(0));
+        let calls = (0..10).map(|_| {
+            let in_flight = in_flight.clone();
+            let relays = relays.clone();
+            tokio::spawn(async move {
+                in_flight
+                    .run("eth:eth_blockNumber:null".to_string(), async {
+                        relays.fetch_add(1, Ordering::AcqRel);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(Bytes::from_static(br#"{"id":1,"result":"0x10"}"#))
+                    })
+                    .await
+            })
+        });
+        for call in futures_util::future::join_all(calls).await {
+            assert!(call.unwrap().is_ok());
+        }
+        assert_eq!(relays.load(Ordering::Acquire), 1);
+        assert!(in_flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keys_fold_only_evm_hex() {
+        let key = |chain: PoktChains, body: &str| match parse_payload(body.as_bytes()).unwrap() {
+            JsonRpcPayload::Single(request) => coalesce_key(chain, &request),
+            JsonRpcPayload::Batch(_) => unreachable!(),
+        };
+        let evm = |params: &str| {
+            format!(r#"{{"jsonrpc":"2.0","id":1,"method":"eth_getBalance","params":{params}}}"#)
+        };
+        assert_eq!(
+            key(PoktChains::Eth, &evm(r#"["0xAbC","latest"]"#)),
+            key(PoktChains::Eth, &evm(r#"["0xabc","latest"]"#))
+        );
+        assert_ne!(
+            key(PoktChains::Eth, &evm(r#"["0xabc","Latest"]"#)),
+            key(PoktChains::Eth, &evm(r#"["0xabc","latest"]"#))
+        );
+
+        let solana = |account: &str| {
+            format!(r#"{{"jsonrpc":"2.0","id":1,"method":"getBalance","params":["{account}"]}}"#)
+        };
+        assert_ne!(
+            key(
+                PoktChains::Solana,
+                &solana("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            ),
+            key(
+                PoktChains::Solana,
+                &solana("9xqewvg816bux9epjhmat23yvvm2zwbrrpzb9pusvfin")
+            )
+        );
+    }
+
+    #[test]
+    fn rewrites_ids() {
+        let response = with_id(
+            br#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#,
+            Some(&json!("a")),
+        )
+        .unwrap();
+        let response: Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": "a", "result": "0x10"})
+        );
+    }
+}
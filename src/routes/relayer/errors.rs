@@ -0,0 +1,140 @@
+use super::types::JsonRpcPayload;
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::Value;
+
+// JSON-RPC 2.0
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INTERNAL_ERROR: i64 = -32603;
+// implementation defined server errors
+pub const UPSTREAM_ERROR: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+// EIP-1474
+pub const METHOD_NOT_SUPPORTED: i64 = -32004;
+pub const LIMIT_EXCEEDED: i64 = -32005;
+
+/// Errors of the `/rpc` path, rendered as JSON-RPC error objects so clients can surface them
+pub trait JsonRpcFailure: std::error::Error {
+    fn code(&self) -> i64;
+    fn status(&self) -> StatusCode;
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonRpcErrorResponse {
+    pub jsonrpc: &'static str,
+    pub id: Value,
+    pub error: JsonRpcError,
+}
+
+impl JsonRpcErrorResponse {
+    pub fn new(id: Option<&Value>, code: i64, message: impl Into<String>) -> Self {
+        JsonRpcErrorResponse {
+            jsonrpc: "2.0",
+            id: id.cloned().unwrap_or(Value::Null),
+            error: JsonRpcError {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+}
+
+/// ids of the calls a failed request carried, echoed back in the error body
+#[derive(Debug, Clone, Default)]
+pub enum RequestIds {
+    // the body could not be parsed
+    #[default]
+    Unknown,
+    Single(Option<Value>),
+    Batch(Vec<Option<Value>>),
+}
+
+impl From<&JsonRpcPayload> for RequestIds {
+    fn from(payload: &JsonRpcPayload) -> Self {
+        match payload {
+            JsonRpcPayload::Single(request) => RequestIds::Single(request.id.clone()),
+            JsonRpcPayload::Batch(batch) => {
+                RequestIds::Batch(batch.iter().map(|request| request.id.clone()).collect())
+            }
+        }
+    }
+}
+
+/// A failed request, answered with one error object per call it carried
+#[derive(Debug)]
+pub struct RpcError {
+    ids: RequestIds,
+    status: StatusCode,
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    pub fn new<E: JsonRpcFailure>(error: E, ids: RequestIds) -> Self {
+        RpcError {
+            ids,
+            status: error.status(),
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for RpcError {
+    fn into_response(self) -> Response {
+        let error = |id: Option<&Value>| JsonRpcErrorResponse::new(id, self.code, &self.message);
+        let body = match &self.ids {
+            RequestIds::Unknown => Json(error(None)).into_response(),
+            RequestIds::Single(id) => Json(error(id.as_ref())).into_response(),
+            RequestIds::Batch(ids) => {
+                Json(ids.iter().map(|id| error(id.as_ref())).collect::<Vec<_>>()).into_response()
+            }
+        };
+        (self.status, body).into_response()
+    }
+}
+
+pub trait WithIds<T> {
+    /// attaches the ids of the request the error belongs to
+    fn with_ids(self, ids: &RequestIds) -> Result<T, RpcError>;
+}
+
+impl<T, E: JsonRpcFailure> WithIds<T> for Result<T, E> {
+    fn with_ids(self, ids: &RequestIds) -> Result<T, RpcError> {
+        self.map_err(|e| RpcError::new(e, ids.clone()))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{RequestIds, RpcError, UNAUTHORIZED};
+    use crate::middleware::rpc_service::RpcAuthErrors;
+    use axum::{http::StatusCode, response::IntoResponse};
+    use http_body_util::BodyExt;
+    use serde_json::{Value, json};
+
+    #[tokio::test]
+    async fn errors_echo_every_id() {
+        let ids = RequestIds::Batch(vec![Some(json!(1)), Some(json!("b"))]);
+        let res = RpcError::new(RpcAuthErrors::InvalidApiKey, ids).into_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["id"], 1);
+        assert_eq!(body[1]["id"], "b");
+        assert_eq!(body[1]["error"]["code"], UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,309 @@
+use super::{
+    types::PoktChains,
+    upstreams::{HTTP_CLIENT, UPSTREAMS, Upstream},
+};
+use futures_util::future::join_all;
+use http::{HeaderValue, header::CONTENT_TYPE};
+use serde_json::{Value, json};
+use std::{
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+/// How often every upstream of every chain is probed
+pub static HEALTH_CHECK_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        dotenvy::var("HEALTH_CHECK_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(15),
+    )
+});
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Consecutive failures that open the breaker
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+/// Error rate in permille that opens the breaker
+const MAX_ERROR_RATE: u32 = 500;
+/// How long an open breaker keeps an upstream out of rotation before it is tried again
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    // cooldown passed, the next outcome decides whether it closes or opens again
+    HalfOpen,
+}
+
+/// Health of a single upstream, fed by relayed traffic and by the background prober
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    consecutive_failures: AtomicU32,
+    // unix millis, 0 when the breaker is closed
+    open_until: AtomicI64,
+    // moving averages, latency in microseconds and error rate in permille
+    latency: AtomicU64,
+    error_rate: AtomicU32,
+    height: AtomicU64,
+    lagging: AtomicBool,
+}
+
+impl UpstreamHealth {
+    pub fn breaker(&self) -> BreakerState {
+        match self.open_until.load(Ordering::Acquire) {
+            0 => BreakerState::Closed,
+            until if until > now_millis() => BreakerState::Open,
+            _ => BreakerState::HalfOpen,
+        }
+    }
+
+    /// whether the upstream should receive traffic
+    pub fn is_available(&self) -> bool {
+        self.breaker() != BreakerState::Open && !self.lagging.load(Ordering::Acquire)
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency.load(Ordering::Acquire))
+    }
+
+    pub fn error_rate(&self) -> u32 {
+        self.error_rate.load(Ordering::Acquire)
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height.load(Ordering::Acquire)
+    }
+
+    pub fn is_lagging(&self) -> bool {
+        self.lagging.load(Ordering::Acquire)
+    }
+
+    pub fn record_success(&self, latency: Duration) {
+        self.consecutive_failures.store(0, Ordering::Release);
+        self.open_until.store(0, Ordering::Release);
+        self.update_error_rate(false);
+        let sample = latency.as_micros() as u64;
+        let old = self.latency.load(Ordering::Acquire);
+        let new = if old == 0 {
+            sample
+        } else {
+            (old * 7 + sample) / 8
+        };
+        self.latency.store(new, Ordering::Release);
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::AcqRel) + 1;
+        let error_rate = self.update_error_rate(true);
+        let half_open = self.breaker() == BreakerState::HalfOpen;
+        if half_open || failures >= MAX_CONSECUTIVE_FAILURES || error_rate > MAX_ERROR_RATE {
+            self.open_until.store(
+                now_millis() + BREAKER_COOLDOWN.as_millis() as i64,
+                Ordering::Release,
+            );
+        }
+    }
+
+    fn update_error_rate(&self, failed: bool) -> u32 {
+        let sample = if failed { 1000 } else { 0 };
+        let old = self.error_rate.load(Ordering::Acquire);
+        let new = (old * 9 + sample) / 10;
+        self.error_rate.store(new, Ordering::Release);
+        new
+    }
+}
+
+fn now_millis() -> i64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+impl PoktChains {
+    /// cheapest call that returns the chain height
+    pub fn head_method(&self) -> &'static str {
+        match self {
+            PoktChains::Solana => "getSlot",
+            PoktChains::Sui => "sui_getLatestCheckpointSequenceNumber",
+            _ => "eth_blockNumber",
+        }
+    }
+
+    /// how far behind the best upstream an upstream may fall before it is taken out of rotation
+    pub fn max_head_lag(&self) -> u64 {
+        match self {
+            // slots and checkpoints are produced several times per second
+            PoktChains::Solana | PoktChains::Sui => 50,
+            _ => 5,
+        }
+    }
+
+    /// blocks behind the head after which a block is not expected to be reorged
+    pub fn reorg_depth(&self) -> u64 {
+        match self {
+            PoktChains::Eth => 64,
+            PoktChains::Poly => 256,
+            PoktChains::Bsc => 15,
+            PoktChains::Solana | PoktChains::Sui => 32,
+            #[cfg(any(test, feature = "dev"))]
+            PoktChains::Anvil => 0,
+            // rollups inherit finality from L1
+            PoktChains::ArbOne | PoktChains::Base | PoktChains::Op => 64,
+        }
+    }
+
+    /// height a majority of the upstreams reached, once a probe succeeded
+    pub fn head_height(&self) -> Option<u64> {
+        majority_height(
+            UPSTREAMS
+                .get(self)?
+                .iter()
+                .map(|upstream| upstream.health.height())
+                .collect(),
+        )
+    }
+
+    /// head minus the reorg depth
+    pub fn finalized_height(&self) -> Option<u64> {
+        Some(self.head_height()?.saturating_sub(self.reorg_depth()))
+    }
+}
+
+/// Background task that probes every upstream of every chain for latency, errors and height
+pub async fn probe_upstreams() {
+    let mut interval = tokio::time::interval(*HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        join_all(
+            UPSTREAMS
+                .iter()
+                .map(|(chain, upstreams)| probe_chain(*chain, upstreams)),
+        )
+        .await;
+    }
+}
+
+async fn probe_chain(chain: PoktChains, upstreams: &[Upstream]) {
+    let heights = join_all(upstreams.iter().map(|upstream| probe(chain, upstream))).await;
+    let Some(head) = majority_height(heights.iter().flatten().copied().collect()) else {
+        warn!("No upstream of {chain} answered the health probe");
+        return;
+    };
+
+    for (upstream, height) in upstreams.iter().zip(heights) {
+        let Some(height) = height else { continue };
+        let lagging = head.saturating_sub(height) > chain.max_head_lag();
+        let was_lagging = upstream.health.lagging.swap(lagging, Ordering::AcqRel);
+        if lagging && !was_lagging {
+            warn!(
+                "Upstream {} of {chain} is {} behind the head",
+                upstream.url,
+                head - height
+            );
+        } else if !lagging && was_lagging {
+            info!("Upstream {} of {chain} caught up", upstream.url);
+        }
+    }
+}
+
+/// The height more than half of the upstreams reached, so a single upstream reporting a bogus
+/// height can neither move the head forward nor mark the others as lagging.
+/// With two upstreams this is the lower one, neither can outvote the other.
+fn majority_height(mut heights: Vec<u64>) -> Option<u64> {
+    heights.retain(|height| *height > 0);
+    heights.sort_unstable_by(|a, b| b.cmp(a));
+    heights.get(heights.len() / 2).copied()
+}
+
+/// returns the height reported by the upstream
+async fn probe(chain: PoktChains, upstream: &Upstream) -> Option<u64> {
+    let body = json!({"jsonrpc": "2.0", "method": chain.head_method(), "params": [], "id": 1});
+    let mut request = HTTP_CLIENT
+        .post(&upstream.url)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .timeout(PROBE_TIMEOUT)
+        .json(&body);
+    if let Some(service_id) = upstream.service_id {
+        request = request.header("target-service-id", service_id);
+    }
+
+    let started = Instant::now();
+    let height = match request.send().await {
+        Ok(res) => match res.error_for_status() {
+            Ok(res) => res
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|v| parse_height(&v)),
+            Err(_) => None,
+        },
+        Err(_) => None,
+    };
+
+    match height {
+        Some(height) => {
+            upstream.health.record_success(started.elapsed());
+            upstream.health.height.store(height, Ordering::Release);
+        }
+        None => {
+            upstream.health.record_failure();
+            warn!("Health probe of {} for {chain} failed", upstream.url);
+        }
+    }
+    height
+}
+
+/// heights come back as hex quantities on EVM chains and as numbers or decimal strings elsewhere
+fn parse_height(response: &Value) -> Option<u64> {
+    match response.get("result")? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{BreakerState, UpstreamHealth, majority_height, parse_height};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_heights() {
+        assert_eq!(parse_height(&json!({"result": "0x10"})), Some(16));
+        assert_eq!(parse_height(&json!({"result": 300})), Some(300));
+        assert_eq!(parse_height(&json!({"result": "1234"})), Some(1234));
+        assert_eq!(parse_height(&json!({"error": {"code": -32000}})), None);
+    }
+
+    #[test]
+    fn bogus_heights_are_outvoted() {
+        assert_eq!(majority_height(vec![100, 101, 99_999]), Some(101));
+        assert_eq!(majority_height(vec![100, 0, 102]), Some(100));
+        assert_eq!(majority_height(vec![100, 102, 102, 5_000]), Some(102));
+        assert_eq!(majority_height(vec![7]), Some(7));
+        assert_eq!(majority_height(vec![0, 0]), None);
+    }
+
+    #[test]
+    fn breaker_opens_and_closes() {
+        let health = UpstreamHealth::default();
+        health.record_success(Duration::from_millis(10));
+        assert_eq!(health.breaker(), BreakerState::Closed);
+        for _ in 0..3 {
+            health.record_failure();
+        }
+        assert_eq!(health.breaker(), BreakerState::Open);
+        assert!(!health.is_available());
+        health.record_success(Duration::from_millis(10));
+        assert_eq!(health.breaker(), BreakerState::Closed);
+        assert!(health.is_available());
+    }
+}
@@ -0,0 +1,495 @@
+use super::{
+    errors::UPSTREAM_ERROR,
+    queue::{ClientQueue, FrameKind},
+    types::{JsonRpcPayload, JsonRpcRequest, PoktChains, Relayer},
+    websockets::{RECONNECT_ATTEMPTS, connect_node, reconnect_delay},
+};
+use crate::routes::usage::{CallStatus, USAGE_ROLLUP, UsageSource};
+use axum::extract::ws::{CloseFrame, Message, Utf8Bytes};
+use futures_util::{SinkExt, StreamExt};
+use rand::{RngExt, rng};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+};
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+use tracing::{info, warn};
+
+/// Upstream subscriptions, shared by every client subscribed to the same topic
+pub static SUBSCRIPTION_HUB: LazyLock<SubscriptionHub> = LazyLock::new(SubscriptionHub::default);
+
+/// Notifications a subscriber may fall behind by before it misses some
+pub const NOTIFICATION_BUFFER: usize = 1024;
+/// How often a shared subscription checks whether anyone still listens to it
+const IDLE_CHECK: Duration = Duration::from_secs(10);
+// the hub sends a single request per upstream socket
+const SUBSCRIBE_ID: u64 = 1;
+/// Most blocks a newHeads subscription backfills after reconnecting, older ones are skipped
+const MAX_BACKFILL: u64 = 64;
+
+/// What a client subscribes to. Clients subscribing to the same topic share one upstream subscription.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic {
+    pub chain: PoktChains,
+    // params of eth_subscribe as JSON, object keys are sorted so equal filters are equal strings
+    pub params: String,
+}
+
+impl Topic {
+    pub fn of(chain: PoktChains, request: &JsonRpcRequest) -> Option<Topic> {
+        (request.method == "eth_subscribe").then(|| Topic {
+            chain,
+            params: request
+                .params
+                .as_ref()
+                .map_or_else(|| "[]".to_string(), Value::to_string),
+        })
+    }
+
+    fn is_new_heads(&self) -> bool {
+        self.params == r#"["newHeads"]"#
+    }
+
+    fn subscribe_request(&self) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":{SUBSCRIBE_ID},"method":"eth_subscribe","params":{}}}"#,
+            self.params
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionState {
+    Pending,
+    Active,
+    // the error object the upstream rejected the subscription with
+    Failed(Value),
+}
+
+struct SharedSubscription {
+    notifications: broadcast::Sender<Arc<str>>,
+    state: watch::Receiver<SubscriptionState>,
+}
+
+/// A client on a shared subscription, dropping it unsubscribes the client
+pub struct Subscriber {
+    // results of the notifications as JSON
+    pub notifications: broadcast::Receiver<Arc<str>>,
+    pub state: watch::Receiver<SubscriptionState>,
+}
+
+#[derive(Default)]
+pub struct SubscriptionHub {
+    topics: Mutex<HashMap<Topic, SharedSubscription>>,
+}
+
+impl SubscriptionHub {
+    /// joins the subscription of a topic, the upstream subscription is opened for the first subscriber
+    pub fn subscribe(&'static self, topic: Topic) -> Subscriber {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(shared) = topics.get(&topic) {
+            return Subscriber {
+                notifications: shared.notifications.subscribe(),
+                state: shared.state.clone(),
+            };
+        }
+
+        let (notifications, receiver) = broadcast::channel(NOTIFICATION_BUFFER);
+        let (state_tx, state) = watch::channel(SubscriptionState::Pending);
+        topics.insert(
+            topic.clone(),
+            SharedSubscription {
+                notifications: notifications.clone(),
+                state: state.clone(),
+            },
+        );
+        tokio::spawn(self.run(topic, notifications, state_tx));
+        Subscriber {
+            notifications: receiver,
+            state,
+        }
+    }
+
+    /// forgets a topic nobody listens to anymore. Checked under the lock new subscribers take,
+    /// so nobody joins a subscription that is about to end.
+    fn release_if_unused(
+        &self,
+        topic: &Topic,
+        notifications: &broadcast::Sender<Arc<str>>,
+    ) -> bool {
+        let mut topics = self.topics.lock().unwrap();
+        let unused = notifications.receiver_count() == 0;
+        if unused {
+            topics.remove(topic);
+        }
+        unused
+    }
+
+    fn remove(&self, topic: &Topic) {
+        self.topics.lock().unwrap().remove(topic);
+    }
+
+    /// holds the upstream subscription of a topic and fans its notifications out
+    async fn run(
+        &'static self,
+        topic: Topic,
+        notifications: broadcast::Sender<Arc<str>>,
+        state: watch::Sender<SubscriptionState>,
+    ) {
+        let mut idle = tokio::time::interval(IDLE_CHECK);
+        // reconnects since the subscription was last confirmed, None before the first connect
+        let mut attempts: Option<u32> = None;
+        // the last block of a newHeads subscription, blocks missed while reconnecting follow it
+        let mut head = None;
+        'node_reconnect: loop {
+            if let Some(attempt) = attempts {
+                // subscribers keep their subscription id while a lost upstream is retried
+                if *state.borrow() != SubscriptionState::Active || attempt >= RECONNECT_ATTEMPTS {
+                    tracing::error!(
+                        "No websocket upstream of {} kept the subscription",
+                        topic.chain
+                    );
+                    state.send_replace(SubscriptionState::Failed(json!({
+                        "code": UPSTREAM_ERROR,
+                        "message": "No upstream is available for this subscription",
+                    })));
+                    break 'node_reconnect;
+                }
+                tokio::time::sleep(reconnect_delay(attempt)).await;
+            }
+            attempts = Some(attempts.map_or(0, |attempt| attempt + 1));
+
+            let Some(node_socket) = connect_node(topic.chain).await else {
+                continue 'node_reconnect;
+            };
+            let (mut node_tx, mut node_rv) = node_socket.split();
+            if let Err(e) = node_tx
+                .send(TungsteniteMessage::Text(topic.subscribe_request().into()))
+                .await
+            {
+                warn!("Failed to subscribe upstream: {e}");
+                continue 'node_reconnect;
+            }
+
+            let mut upstream_id = None;
+            let mut gap_from = head.map(|head: u64| head + 1);
+            loop {
+                select! {
+                    msg = node_rv.next() => match msg {
+                        Some(Ok(TungsteniteMessage::Text(text))) => match UpstreamEvent::parse(&text) {
+                            Some(UpstreamEvent::Subscribed(id)) => {
+                                upstream_id = Some(id);
+                                attempts = Some(0);
+                                state.send_replace(SubscriptionState::Active);
+                            }
+                            Some(UpstreamEvent::Rejected(error)) => {
+                                state.send_replace(SubscriptionState::Failed(error));
+                                break 'node_reconnect;
+                            }
+                            Some(UpstreamEvent::Notification { subscription, result })
+                                if upstream_id.as_ref() == Some(&subscription) =>
+                            {
+                                if topic.is_new_heads()
+                                    && let Some(number) = block_number(&result)
+                                {
+                                    if let Some(from) = gap_from.take()
+                                        && number > from
+                                    {
+                                        for block in backfill(topic.chain, from, number).await {
+                                            let _ = notifications.send(block);
+                                        }
+                                    }
+                                    head = Some(number);
+                                }
+                                // no receivers is fine, the idle check cleans up
+                                let _ = notifications.send(result);
+                            }
+                            _ => {}
+                        },
+                        Some(Ok(TungsteniteMessage::Close(_))) | Some(Err(_)) | None => {
+                            warn!("Lost the shared subscription upstream of {}. Reconnecting ...", topic.chain);
+                            continue 'node_reconnect;
+                        }
+                        Some(Ok(_)) => {}
+                    },
+                    _ = idle.tick() => {
+                        if self.release_if_unused(&topic, &notifications) {
+                            if let Some(id) = upstream_id {
+                                let unsubscribe = json!({
+                                    "jsonrpc": "2.0",
+                                    "id": SUBSCRIBE_ID + 1,
+                                    "method": "eth_unsubscribe",
+                                    "params": [id],
+                                });
+                                let _ = node_tx
+                                    .send(TungsteniteMessage::Text(unsubscribe.to_string().into()))
+                                    .await;
+                            }
+                            let _ = node_tx.send(TungsteniteMessage::Close(None)).await;
+                            info!("Closed an unused subscription of {}", topic.chain);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        // subscribers see the failure and the end of the channel, later ones start over
+        self.remove(&topic);
+    }
+}
+
+fn block_number(header: &str) -> Option<u64> {
+    let header: Value = serde_json::from_str(header).ok()?;
+    let number = header.get("number")?.as_str()?;
+    u64::from_str_radix(number.trim_start_matches("0x"), 16).ok()
+}
+
+/// blocks a newHeads subscription missed while it reconnected, oldest first and shaped like headers
+async fn backfill(chain: PoktChains, from: u64, to: u64) -> Vec<Arc<str>> {
+    let from = from.max(to.saturating_sub(MAX_BACKFILL));
+    let batch = (from..to)
+        .map(|number| JsonRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            method: "eth_getBlockByNumber".to_string(),
+            params: Some(json!([format!("{number:#x}"), false])),
+            id: Some(json!(number)),
+        })
+        .collect();
+    let blocks = match chain.relay_transaction(&JsonRpcPayload::Batch(batch)).await {
+        Ok(body) => axum::body::to_bytes(body, usize::MAX).await.ok(),
+        Err(e) => {
+            warn!("Failed to backfill blocks {from} to {to} of {chain}: {e}");
+            None
+        }
+    };
+    let Some(mut blocks) =
+        blocks.and_then(|bytes| serde_json::from_slice::<Vec<Value>>(&bytes).ok())
+    else {
+        return Vec::new();
+    };
+    blocks.sort_by_key(|block| block["id"].as_u64());
+    blocks
+        .into_iter()
+        .filter_map(|mut block| {
+            let header = block.get_mut("result")?.as_object_mut()?;
+            header.remove("transactions");
+            header.remove("uncles");
+            Some(Value::Object(header.clone()).to_string().into())
+        })
+        .collect()
+}
+
+/// A message of an upstream socket the hub cares about
+#[derive(Debug, PartialEq)]
+enum UpstreamEvent {
+    Subscribed(String),
+    Rejected(Value),
+    Notification {
+        subscription: String,
+        result: Arc<str>,
+    },
+}
+
+#[derive(Deserialize)]
+struct UpstreamMessage {
+    id: Option<Value>,
+    result: Option<Value>,
+    error: Option<Value>,
+    params: Option<NotificationParams>,
+}
+
+#[derive(Deserialize)]
+struct NotificationParams {
+    subscription: String,
+    result: Value,
+}
+
+impl UpstreamEvent {
+    fn parse(text: &str) -> Option<UpstreamEvent> {
+        let message: UpstreamMessage = serde_json::from_str(text).ok()?;
+        if let Some(params) = message.params {
+            return Some(UpstreamEvent::Notification {
+                subscription: params.subscription,
+                result: params.result.to_string().into(),
+            });
+        }
+        if message.id != Some(json!(SUBSCRIBE_ID)) {
+            return None;
+        }
+        match (message.result, message.error) {
+            (_, Some(error)) => Some(UpstreamEvent::Rejected(error)),
+            (Some(Value::String(id)), None) => Some(UpstreamEvent::Subscribed(id)),
+            _ => None,
+        }
+    }
+}
+
+/// subscription ids the proxy hands out, shaped like the ones of geth
+pub fn client_subscription_id() -> String {
+    format!("0x{}", hex::encode(rng().random::<[u8; 16]>()))
+}
+
+/// a notification as the client sees it, under its own subscription id
+fn notification_text(subscription: &str, result: &str) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","method":"eth_subscription","params":{{"subscription":"{subscription}","result":{result}}}}}"#
+    )
+}
+
+/// subscribes a client of a session to a topic and forwards the notifications under its own id
+pub async fn forward_subscription(
+    topic: Topic,
+    request: JsonRpcRequest,
+    subscription: String,
+    source: Option<UsageSource>,
+    outbound: Arc<ClientQueue>,
+) {
+    let Subscriber {
+        mut notifications,
+        mut state,
+    } = SUBSCRIPTION_HUB.subscribe(topic.clone());
+    let ready = state
+        .wait_for(|state| *state != SubscriptionState::Pending)
+        .await
+        .map(|state| state.clone());
+
+    let response = match ready {
+        Ok(SubscriptionState::Active) => {
+            json!({"jsonrpc": "2.0", "id": request.id, "result": subscription})
+        }
+        Ok(SubscriptionState::Failed(error)) => {
+            json!({"jsonrpc": "2.0", "id": request.id, "error": error})
+        }
+        _ => json!({
+            "jsonrpc": "2.0",
+            "id": request.id,
+            "error": {"code": UPSTREAM_ERROR, "message": "The subscription ended"},
+        }),
+    };
+    let active = response.get("result").is_some();
+    if let Some(source) = &source {
+        let status = match active {
+            true => CallStatus::Success,
+            false => CallStatus::UpstreamError,
+        };
+        USAGE_ROLLUP.record(
+            source,
+            topic.chain,
+            &JsonRpcPayload::Single(request),
+            status,
+        );
+    }
+    if outbound
+        .send(Message::Text(response.to_string().into()))
+        .is_err()
+        || !active
+    {
+        return;
+    }
+
+    // heads of a slow client may be coalesced into the latest one
+    let kind = match topic.is_new_heads() {
+        true => FrameKind::Head(subscription.clone()),
+        false => FrameKind::Notification,
+    };
+    loop {
+        match notifications.recv().await {
+            Ok(result) => {
+                let text = notification_text(&subscription, &result);
+                if outbound
+                    .push(kind.clone(), Message::Text(text.into()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Subscriber fell behind and missed {missed} notifications");
+                outbound.missed(missed);
+            }
+            Err(RecvError::Closed) => {
+                let _ = outbound.send(upstream_gone());
+                return;
+            }
+        }
+    }
+}
+
+pub fn upstream_gone() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: 1011,
+        reason: Utf8Bytes::from_static("Upstream subscription ended"),
+    }))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{Topic, UpstreamEvent, block_number, notification_text};
+    use crate::routes::relayer::types::{JsonRpcRequest, PoktChains};
+    use serde_json::{Value, json};
+
+    fn request(method: &str, params: Value) -> JsonRpcRequest {
+        serde_json::from_value(
+            json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 7}),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn equal_filters_share_a_topic() {
+        let logs = |filter: &str| {
+            let params: Value = serde_json::from_str(filter).unwrap();
+            Topic::of(PoktChains::Base, &request("eth_subscribe", params)).unwrap()
+        };
+        assert_eq!(
+            logs(r#"["logs", {"address": "0xabc", "topics": []}]"#),
+            logs(r#"["logs", {"topics": [], "address": "0xabc"}]"#)
+        );
+        assert_ne!(
+            logs(r#"["logs", {"address": "0xabc"}]"#),
+            logs(r#"["logs", {"address": "0xdef"}]"#)
+        );
+        assert!(Topic::of(PoktChains::Base, &request("eth_call", json!([]))).is_none());
+    }
+
+    #[test]
+    fn rewrites_upstream_messages() {
+        assert_eq!(
+            UpstreamEvent::parse(r#"{"jsonrpc":"2.0","id":1,"result":"0xupstream"}"#),
+            Some(UpstreamEvent::Subscribed("0xupstream".to_string()))
+        );
+        assert!(matches!(
+            UpstreamEvent::parse(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"bad"}}"#
+            ),
+            Some(UpstreamEvent::Rejected(_))
+        ));
+        let Some(UpstreamEvent::Notification {
+            subscription,
+            result,
+        }) = UpstreamEvent::parse(
+            r#"{"jsonrpc":"2.0","method":"eth_subscription","params":{"subscription":"0xupstream","result":{"number":"0x1"}}}"#,
+        )
+        else {
+            panic!("not a notification");
+        };
+        assert_eq!(subscription, "0xupstream");
+
+        assert_eq!(block_number(&result), Some(1));
+
+        let text = notification_text("0xclient", &result);
+        let notification: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(notification["params"]["subscription"], "0xclient");
+        assert_eq!(notification["params"]["result"]["number"], "0x1");
+    }
+}
@@ -0,0 +1,175 @@
+use super::{
+    coalesce::with_id,
+    types::{JsonRpcRequest, PoktChains, RelayErrors},
+    upstreams::{self, RELAY_TIMEOUT},
+};
+use axum::body::Bytes;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use serde_json::{Value, json};
+use std::{sync::LazyLock, time::Instant};
+
+/// Widest block range a single `eth_getLogs` call may span upstream
+pub static MAX_LOG_RANGE: LazyLock<u64> = LazyLock::new(|| {
+    dotenvy::var("MAX_LOG_RANGE")
+        .ok()
+        .and_then(|blocks| blocks.parse().ok())
+        .filter(|blocks| *blocks > 0)
+        .unwrap_or(2_000)
+});
+
+/// How many chunks of a split `eth_getLogs` call are relayed at once
+pub static LOG_SPLIT_CONCURRENCY: LazyLock<usize> = LazyLock::new(|| {
+    dotenvy::var("LOG_SPLIT_CONCURRENCY")
+        .ok()
+        .and_then(|chunks| chunks.parse().ok())
+        .filter(|chunks| *chunks > 0)
+        .unwrap_or(4)
+});
+
+/// Most chunks an `eth_getLogs` call may be split into, wider calls are refused
+pub static MAX_LOG_CHUNKS: LazyLock<u64> = LazyLock::new(|| {
+    dotenvy::var("MAX_LOG_CHUNKS")
+        .ok()
+        .and_then(|chunks| chunks.parse().ok())
+        .filter(|chunks| *chunks > 0)
+        .unwrap_or(50)
+});
+
+/// Widest block range an `eth_getLogs` call may span
+pub fn max_log_span() -> u64 {
+    MAX_LOG_RANGE.saturating_mul(*MAX_LOG_CHUNKS)
+}
+
+/// Block ranges an `eth_getLogs` call is split into, None if it fits into a single
+/// upstream call, its range isn't known or it is too wide to be relayed at all
+pub fn log_chunks(chain: PoktChains, request: &JsonRpcRequest) -> Option<Vec<(u64, u64)>> {
+    let (from, to) = log_range(chain, request)?;
+    let chunks = chunk_count(from, to, *MAX_LOG_RANGE);
+    (chunks > 1 && chunks <= *MAX_LOG_CHUNKS).then(|| split_range(from, to, *MAX_LOG_RANGE))
+}
+
+/// whether an `eth_getLogs` call spans more blocks than [`max_log_span`]
+pub fn exceeds_log_span(chain: PoktChains, request: &JsonRpcRequest) -> bool {
+    log_range(chain, request)
+        .is_some_and(|(from, to)| chunk_count(from, to, *MAX_LOG_RANGE) > *MAX_LOG_CHUNKS)
+}
+
+fn log_range(chain: PoktChains, request: &JsonRpcRequest) -> Option<(u64, u64)> {
+    if request.method != "eth_getLogs" {
+        return None;
+    }
+    let filter = request.params.as_ref()?.get(0)?;
+    block_range(filter, || chain.head_height())
+}
+
+// filters by block hash target a single block, tags other than latest aren't resolved
+fn block_range(filter: &Value, head: impl FnOnce() -> Option<u64>) -> Option<(u64, u64)> {
+    if filter.get("blockHash").is_some() {
+        return None;
+    }
+    let from = match filter.get("fromBlock")?.as_str()? {
+        "earliest" => 0,
+        from => u64::from_str_radix(from.strip_prefix("0x")?, 16).ok()?,
+    };
+    let to = match filter.get("toBlock").and_then(Value::as_str) {
+        None | Some("latest") => head()?,
+        Some(to) => u64::from_str_radix(to.strip_prefix("0x")?, 16).ok()?,
+    };
+    (from <= to).then_some((from, to))
+}
+
+fn chunk_count(from: u64, to: u64, max: u64) -> u64 {
+    (to - from) / max + 1
+}
+
+fn split_range(from: u64, to: u64, max: u64) -> Vec<(u64, u64)> {
+    let mut chunks = vec![];
+    let mut start = from;
+    loop {
+        let end = to.min(start.saturating_add(max - 1));
+        chunks.push((start, end));
+        if end >= to {
+            return chunks;
+        }
+        start = end + 1;
+    }
+}
+
+/// Relays every chunk of a split `eth_getLogs` call and merges the logs in block order.
+/// The first chunk that fails is returned as the response of the whole call.
+/// All chunks share the deadline of a single relay.
+pub async fn relay_split(
+    chain: PoktChains,
+    request: &JsonRpcRequest,
+    chunks: Vec<(u64, u64)>,
+) -> Result<Bytes, RelayErrors> {
+    let deadline = Instant::now() + *RELAY_TIMEOUT;
+    let responses: Vec<Bytes> = stream::iter(chunks)
+        .map(|(from, to)| relay_chunk(chain, request, from, to, deadline))
+        .buffered(*LOG_SPLIT_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let mut logs = vec![];
+    for response in responses {
+        let response: Value = serde_json::from_slice(&response)?;
+        match response.get("result") {
+            Some(Value::Array(chunk)) => logs.extend(chunk.iter().cloned()),
+            _ => return with_id(&serde_json::to_vec(&response)?, request.id.as_ref()),
+        }
+    }
+
+    let merged = json!({"jsonrpc": "2.0", "id": request.id, "result": logs});
+    Ok(Bytes::from(serde_json::to_vec(&merged)?))
+}
+
+async fn relay_chunk(
+    chain: PoktChains,
+    request: &JsonRpcRequest,
+    from: u64,
+    to: u64,
+    deadline: Instant,
+) -> Result<Bytes, RelayErrors> {
+    let mut chunk = request.clone();
+    if let Some(filter) = chunk
+        .params
+        .as_mut()
+        .and_then(|params| params.get_mut(0))
+        .and_then(Value::as_object_mut)
+    {
+        filter.insert("fromBlock".to_string(), json!(format!("{from:#x}")));
+        filter.insert("toBlock".to_string(), json!(format!("{to:#x}")));
+    }
+    let body = Bytes::from(serde_json::to_vec(&chunk)?);
+    let res = upstreams::relay_until(chain, body, true, deadline).await?;
+    Ok(res.bytes().await?)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{block_range, chunk_count, split_range};
+    use serde_json::json;
+
+    #[test]
+    fn splits_ranges() {
+        assert_eq!(split_range(0, 9, 5), [(0, 4), (5, 9)]);
+        assert_eq!(split_range(10, 12, 5), [(10, 12)]);
+        assert_eq!(split_range(0, 10, 5), [(0, 4), (5, 9), (10, 10)]);
+        assert_eq!(chunk_count(0, 10, 5), 3);
+        assert_eq!(chunk_count(0, 9, 5), 2);
+        assert_eq!(chunk_count(0, u64::MAX, 2_000), u64::MAX / 2_000 + 1);
+    }
+
+    #[test]
+    fn reads_block_ranges() {
+        let filter = json!({"fromBlock": "0x10", "toBlock": "0x20"});
+        assert_eq!(block_range(&filter, || None), Some((16, 32)));
+        let filter = json!({"fromBlock": "0x10", "toBlock": "latest"});
+        assert_eq!(block_range(&filter, || Some(100)), Some((16, 100)));
+        assert_eq!(block_range(&filter, || None), None);
+        let filter = json!({"blockHash": "0xabc"});
+        assert_eq!(block_range(&filter, || Some(100)), None);
+        let filter = json!({"fromBlock": "0x20", "toBlock": "0x10"});
+        assert_eq!(block_range(&filter, || None), None);
+    }
+}
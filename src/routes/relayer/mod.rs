@@ -1,3 +1,12 @@
+pub mod cache;
+pub mod coalesce;
+pub mod errors;
+pub mod health;
+pub mod hub;
+pub mod logs;
+pub mod policy;
+pub mod queue;
 pub mod router;
 pub mod types;
+pub mod upstreams;
 pub mod websockets;
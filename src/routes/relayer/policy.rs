@@ -0,0 +1,223 @@
+use super::{
+    errors::{JsonRpcErrorResponse, LIMIT_EXCEEDED, METHOD_NOT_FOUND, UNAUTHORIZED},
+    logs::{exceeds_log_span, max_log_span},
+    types::{JsonRpcPayload, JsonRpcRequest, PoktChains},
+};
+use crate::routes::api_keys::KeyScope;
+use serde_json::Value;
+
+/// Namespaces that administer or sign on the node, never relayed for any chain
+const DENIED_PREFIXES: [&str; 6] = [
+    "admin_",
+    "personal_",
+    "miner_",
+    "engine_",
+    "clique_",
+    "txpool_",
+];
+
+const EVM_PREFIXES: [&str; 5] = ["eth_", "net_", "web3_", "debug_", "trace_"];
+const SUI_PREFIXES: [&str; 2] = ["sui_", "suix_"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainFamily {
+    Evm,
+    Solana,
+    Sui,
+}
+
+impl PoktChains {
+    pub fn family(&self) -> ChainFamily {
+        match self {
+            PoktChains::Solana => ChainFamily::Solana,
+            PoktChains::Sui => ChainFamily::Sui,
+            _ => ChainFamily::Evm,
+        }
+    }
+
+    /// whether a method belongs to the chain's API and is safe to expose
+    pub fn allows_method(&self, method: &str) -> bool {
+        if DENIED_PREFIXES
+            .iter()
+            .any(|prefix| method.starts_with(prefix))
+        {
+            return false;
+        }
+        match self.family() {
+            ChainFamily::Evm => EVM_PREFIXES.iter().any(|prefix| method.starts_with(prefix)),
+            // solana methods are plain camelCase, e.g. getSlot
+            ChainFamily::Solana => {
+                method.starts_with(|c: char| c.is_ascii_lowercase())
+                    && method.chars().all(|c| c.is_ascii_alphanumeric())
+            }
+            ChainFamily::Sui => SUI_PREFIXES.iter().any(|prefix| method.starts_with(prefix)),
+        }
+    }
+}
+
+impl JsonRpcRequest {
+    /// a JSON-RPC 2.0 request object, params by position or by name and a scalar id
+    pub fn is_valid(&self) -> bool {
+        self.jsonrpc == "2.0"
+            && !self.method.is_empty()
+            && matches!(self.params, None | Some(Value::Array(_) | Value::Object(_)))
+            && matches!(
+                self.id,
+                None | Some(Value::Null | Value::String(_) | Value::Number(_))
+            )
+    }
+}
+
+/// The error a call is answered with if the chain does not serve it,
+/// the scope of the api key does not allow it or it asks for too much
+pub fn denial(
+    chain: PoktChains,
+    scope: Option<&KeyScope>,
+    request: &JsonRpcRequest,
+) -> Option<JsonRpcErrorResponse> {
+    if !chain.allows_method(&request.method) {
+        return Some(JsonRpcErrorResponse::new(
+            request.id.as_ref(),
+            METHOD_NOT_FOUND,
+            format!("Method {} is not supported on {chain}", request.method),
+        ));
+    }
+    if scope.is_some_and(|scope| !scope.allows(request)) {
+        return Some(JsonRpcErrorResponse::new(
+            request.id.as_ref(),
+            UNAUTHORIZED,
+            format!("Method {} is not allowed for this api key", request.method),
+        ));
+    }
+    if exceeds_log_span(chain, request) {
+        return Some(JsonRpcErrorResponse::new(
+            request.id.as_ref(),
+            LIMIT_EXCEEDED,
+            format!("eth_getLogs may span at most {} blocks", max_log_span()),
+        ));
+    }
+    None
+}
+
+/// Splits off the calls the chain does not serve or the key may not make. What remains is relayed,
+/// every denied call is answered with its own error object, except notifications which are
+/// never answered.
+pub fn apply_policy(
+    chain: PoktChains,
+    scope: Option<&KeyScope>,
+    payload: JsonRpcPayload,
+) -> (Option<JsonRpcPayload>, Vec<JsonRpcErrorResponse>) {
+    match payload {
+        JsonRpcPayload::Single(request) => match denial(chain, scope, &request) {
+            Some(error) if request.id.is_some() => (None, vec![error]),
+            Some(_) => (None, vec![]),
+            None => (Some(JsonRpcPayload::Single(request)), vec![]),
+        },
+        JsonRpcPayload::Batch(batch) => {
+            let mut allowed = vec![];
+            let mut errors = vec![];
+            for request in batch {
+                match denial(chain, scope, &request) {
+                    Some(error) if request.id.is_some() => errors.push(error),
+                    Some(_) => {}
+                    None => allowed.push(request),
+                }
+            }
+            let allowed = (!allowed.is_empty()).then_some(JsonRpcPayload::Batch(allowed));
+            (allowed, errors)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{KeyScope, LIMIT_EXCEEDED, UNAUTHORIZED, apply_policy};
+    use crate::routes::relayer::{router::parse_payload, types::PoktChains};
+
+    #[test]
+    fn methods_follow_the_chain() {
+        assert!(PoktChains::Eth.allows_method("eth_getLogs"));
+        assert!(!PoktChains::Eth.allows_method("getSlot"));
+        assert!(!PoktChains::Eth.allows_method("admin_peers"));
+        assert!(!PoktChains::Base.allows_method("personal_sign"));
+        assert!(PoktChains::Solana.allows_method("getSlot"));
+        assert!(!PoktChains::Solana.allows_method("eth_blockNumber"));
+        assert!(PoktChains::Sui.allows_method("suix_getBalance"));
+        assert!(!PoktChains::Sui.allows_method("miner_start"));
+    }
+
+    #[test]
+    fn denied_calls_are_split_off() {
+        let payload = parse_payload(
+            br#"[
+                {"jsonrpc":"2.0","method":"eth_chainId","id":1},
+                {"jsonrpc":"2.0","method":"admin_peers","id":2}
+            ]"#,
+        )
+        .unwrap();
+        let (allowed, denied) = apply_policy(PoktChains::Eth, None, payload);
+        assert_eq!(allowed.unwrap().len(), 1);
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].id, 2);
+
+        let payload = parse_payload(br#"{"jsonrpc":"2.0","method":"miner_stop","id":1}"#).unwrap();
+        let (allowed, denied) = apply_policy(PoktChains::Eth, None, payload);
+        assert!(allowed.is_none());
+        assert_eq!(denied.len(), 1);
+
+        let payload = parse_payload(
+            br#"{"jsonrpc":"2.0","method":"eth_getLogs","params":[{"fromBlock":"0x0","toBlock":"0xffffffffff"}],"id":1}"#,
+        )
+        .unwrap();
+        let (allowed, denied) = apply_policy(PoktChains::Eth, None, payload);
+        assert!(allowed.is_none());
+        assert_eq!(denied[0].error.code, LIMIT_EXCEEDED);
+
+        // notifications are dropped without an answer
+        let payload = parse_payload(
+            br#"[
+                {"jsonrpc":"2.0","method":"eth_chainId","id":1},
+                {"jsonrpc":"2.0","method":"admin_peers"}
+            ]"#,
+        )
+        .unwrap();
+        let (allowed, denied) = apply_policy(PoktChains::Eth, None, payload);
+        assert_eq!(allowed.unwrap().len(), 1);
+        assert!(denied.is_empty());
+
+        let payload = parse_payload(br#"{"jsonrpc":"2.0","method":"miner_stop"}"#).unwrap();
+        let (allowed, denied) = apply_policy(PoktChains::Eth, None, payload);
+        assert!(allowed.is_none());
+        assert!(denied.is_empty());
+    }
+
+    #[test]
+    fn scopes_restrict_methods() {
+        let payload = parse_payload(
+            br#"[
+                {"jsonrpc":"2.0","method":"eth_getBalance","params":[],"id":1},
+                {"jsonrpc":"2.0","method":"eth_sendRawTransaction","params":["0x00"],"id":2},
+                {"jsonrpc":"2.0","method":"eth_call","params":[],"id":3}
+            ]"#,
+        )
+        .unwrap();
+        let scope = KeyScope {
+            allowed_methods: Some(vec!["eth_get*".to_string(), "eth_send*".to_string()]),
+            read_only: true,
+            ..Default::default()
+        };
+        let (allowed, denied) = apply_policy(PoktChains::Eth, Some(&scope), payload);
+        assert_eq!(allowed.unwrap().requests()[0].method, "eth_getBalance");
+        assert_eq!(denied.len(), 2);
+        assert_eq!(denied[0].error.code, UNAUTHORIZED);
+        assert!(scope.allows_chain(PoktChains::Eth));
+
+        let scope = KeyScope {
+            chains: Some(vec![PoktChains::Base]),
+            denied_methods: Some(vec!["eth_call".to_string()]),
+            ..Default::default()
+        };
+        assert!(!scope.allows_chain(PoktChains::Eth));
+        assert!(scope.allows_chain(PoktChains::Base));
+    }
+}
@@ -0,0 +1,348 @@
+use super::types::PoktChains;
+use crate::routes::{types::Claims, usage::UsageSource};
+use axum::{
+    Json,
+    extract::{Extension, ws::CloseFrame, ws::Message, ws::Utf8Bytes},
+    response::IntoResponse,
+};
+use jwt_simple::claims::JWTClaims;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+/// Frames a websocket client may fall behind by before the overflow policy applies
+pub static QUEUE_CAPACITY: LazyLock<usize> = LazyLock::new(|| {
+    dotenvy::var("WS_QUEUE_CAPACITY")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(1024)
+        .max(1)
+});
+
+/// What happens to clients that fall behind, see [`OverflowPolicy`]
+pub static OVERFLOW_POLICY: LazyLock<OverflowPolicy> = LazyLock::new(|| {
+    dotenvy::var("WS_OVERFLOW_POLICY")
+        .ok()
+        .and_then(|policy| policy.parse().ok())
+        .unwrap_or_default()
+});
+
+/// Queues of the open websocket sessions, so their owners can see how far behind they are
+pub static OPEN_SESSIONS: LazyLock<OpenSessions> = LazyLock::new(OpenSessions::default);
+
+/// How a full queue makes room for a notification. Responses are never dropped,
+/// a client that can't be helped otherwise is disconnected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // the oldest queued notification is dropped
+    DropOldest,
+    // a newHeads notification replaces the queued one of its subscription
+    CoalesceHeads,
+    #[default]
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "coalesce_heads" => Ok(OverflowPolicy::CoalesceHeads),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What a queued frame is, only notifications may be dropped or coalesced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameKind {
+    Response,
+    Notification,
+    // a newHeads notification of the client subscription with this id
+    Head(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub peak_depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+/// The client went away or was disconnected for falling behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueClosed;
+
+#[derive(Debug, Default)]
+struct QueueState {
+    frames: VecDeque<(FrameKind, Message)>,
+    stats: QueueStats,
+    closed: bool,
+}
+
+/// Frames waiting to be written to a websocket client, bounded so a slow client can't
+/// grow memory without limit
+#[derive(Debug)]
+pub struct ClientQueue {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<QueueState>,
+    ready: Notify,
+    closing: Notify,
+}
+
+impl ClientQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        ClientQueue {
+            capacity,
+            policy,
+            state: Mutex::new(QueueState::default()),
+            ready: Notify::new(),
+            closing: Notify::new(),
+        }
+    }
+
+    /// queues a response or control frame
+    pub fn send(&self, message: Message) -> Result<(), QueueClosed> {
+        self.push(FrameKind::Response, message)
+    }
+
+    pub fn push(&self, kind: FrameKind, message: Message) -> Result<(), QueueClosed> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(QueueClosed);
+        }
+        // control frames don't wait behind the limit
+        let control = matches!(
+            message,
+            Message::Close(_) | Message::Ping(_) | Message::Pong(_)
+        );
+        if !control && state.frames.len() >= self.capacity && !self.make_room(&mut state, &kind) {
+            state.frames.clear();
+            state.frames.push_back((FrameKind::Response, fell_behind()));
+            state.closed = true;
+            drop(state);
+            self.ready.notify_one();
+            self.closing.notify_waiters();
+            return Err(QueueClosed);
+        }
+
+        state.frames.push_back((kind, message));
+        state.stats.peak_depth = state.stats.peak_depth.max(state.frames.len());
+        drop(state);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    fn make_room(&self, state: &mut QueueState, kind: &FrameKind) -> bool {
+        let oldest = match (self.policy, kind) {
+            (OverflowPolicy::DropOldest, _) => state
+                .frames
+                .iter()
+                .position(|(queued, _)| *queued != FrameKind::Response),
+            (OverflowPolicy::CoalesceHeads, FrameKind::Head(_)) => {
+                state.frames.iter().position(|(queued, _)| queued == kind)
+            }
+            _ => None,
+        };
+        let Some(oldest) = oldest else {
+            return false;
+        };
+        state.frames.remove(oldest);
+        match self.policy {
+            OverflowPolicy::CoalesceHeads => state.stats.coalesced += 1,
+            _ => state.stats.dropped += 1,
+        }
+        true
+    }
+
+    /// counts notifications the client missed before they reached the queue
+    pub fn missed(&self, notifications: u64) {
+        self.state.lock().unwrap().stats.dropped += notifications;
+    }
+
+    pub fn try_pop(&self) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        state.frames.pop_front().map(|(_, message)| message)
+    }
+
+    /// the next frame for the client, None once the queue is closed and drained
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.try_pop() {
+                return Some(message);
+            }
+            if self.is_closed() {
+                return None;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// stops taking frames, the ones queued are still written
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+        self.closing.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub async fn closed(&self) {
+        let closing = self.closing.notified();
+        if self.is_closed() {
+            return;
+        }
+        closing.await;
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            depth: state.frames.len(),
+            ..state.stats
+        }
+    }
+}
+
+fn fell_behind() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: 1008,
+        reason: Utf8Bytes::from_static("Client fell too far behind"),
+    }))
+}
+
+struct OpenSession {
+    email: String,
+    prefix: String,
+    chain: PoktChains,
+    opened: i64,
+    queue: Arc<ClientQueue>,
+}
+
+#[derive(Default)]
+pub struct OpenSessions {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, OpenSession>>,
+}
+
+impl OpenSessions {
+    pub fn register(
+        &'static self,
+        source: &UsageSource,
+        chain: PoktChains,
+        queue: Arc<ClientQueue>,
+    ) -> SessionEntry {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().unwrap().insert(
+            id,
+            OpenSession {
+                email: source.email.clone(),
+                prefix: source.prefix.clone(),
+                chain,
+                opened: OffsetDateTime::now_utc().unix_timestamp(),
+                queue,
+            },
+        );
+        SessionEntry { sessions: self, id }
+    }
+
+    fn of(&self, email: &str) -> Vec<SessionQueue> {
+        let mut sessions: Vec<SessionQueue> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.email == email)
+            .map(|session| SessionQueue {
+                key: session.prefix.clone(),
+                chain: session.chain,
+                opened: session.opened,
+                stats: session.queue.stats(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.opened);
+        sessions
+    }
+}
+
+/// A session listed in [`OPEN_SESSIONS`], removed when dropped
+pub struct SessionEntry {
+    sessions: &'static OpenSessions,
+    id: u64,
+}
+
+impl Drop for SessionEntry {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionQueue {
+    key: String,
+    chain: PoktChains,
+    // unix time
+    opened: i64,
+    #[serde(flatten)]
+    stats: QueueStats,
+}
+
+/// open websocket sessions of the caller's keys and how far behind their clients are
+#[tracing::instrument(skip(jwt))]
+pub async fn get_ws_sessions(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+) -> impl IntoResponse {
+    Json(OPEN_SESSIONS.of(jwt.custom.email.as_str()))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{ClientQueue, FrameKind, OverflowPolicy};
+    use axum::extract::ws::Message;
+
+    fn text(text: &str) -> Message {
+        Message::Text(text.into())
+    }
+
+    #[test]
+    fn full_queues_apply_the_policy() {
+        let queue = ClientQueue::new(2, OverflowPolicy::DropOldest);
+        queue.send(text("response")).unwrap();
+        queue.push(FrameKind::Notification, text("first")).unwrap();
+        queue.push(FrameKind::Notification, text("second")).unwrap();
+        assert_eq!(queue.try_pop(), Some(text("response")));
+        assert_eq!(queue.try_pop(), Some(text("second")));
+        assert_eq!(queue.stats().dropped, 1);
+
+        let queue = ClientQueue::new(2, OverflowPolicy::CoalesceHeads);
+        let head = || FrameKind::Head("0xsub".to_string());
+        queue.push(head(), text("0x1")).unwrap();
+        queue.send(text("response")).unwrap();
+        queue.push(head(), text("0x2")).unwrap();
+        assert_eq!(queue.try_pop(), Some(text("response")));
+        assert_eq!(queue.try_pop(), Some(text("0x2")));
+        assert_eq!(queue.stats().coalesced, 1);
+        assert_eq!(queue.stats().peak_depth, 2);
+
+        // nothing to make room with, the client is disconnected
+        queue.push(FrameKind::Notification, text("a")).unwrap();
+        queue.push(FrameKind::Notification, text("b")).unwrap();
+        assert!(queue.push(FrameKind::Notification, text("c")).is_err());
+        assert!(matches!(queue.try_pop(), Some(Message::Close(Some(frame))) if frame.code == 1008));
+        assert!(queue.is_closed());
+    }
+}
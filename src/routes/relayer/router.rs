@@ -1,19 +1,292 @@
 use std::{
     collections::HashMap,
     sync::{
-        Arc, RwLock,
-        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, RwLock,
+        atomic::{AtomicI64, Ordering},
     },
 };
 
-use super::types::RelayErrors;
-use crate::routes::relayer::types::{PoktChains, Relayer};
-use axum::{body::Bytes, extract::Path, http::StatusCode, response::IntoResponse};
+use super::{
+    errors::{
+        INVALID_REQUEST, JsonRpcFailure, PARSE_ERROR, RequestIds, RpcError, UPSTREAM_ERROR, WithIds,
+    },
+    policy::apply_policy,
+    types::RelayErrors,
+};
+use crate::{
+    database::types::Plan,
+    middleware::{compute_units::Metered, rpc_service::RpcAuthErrors},
+    routes::{
+        api_keys::{ApiKeyHash, KeyScope},
+        relayer::types::{JsonRpcPayload, JsonRpcRequest, MAX_BATCH_SIZE, PoktChains, Relayer},
+        usage::{CallStatus, USAGE_ROLLUP, UsageSource},
+    },
+};
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Extension, FromRequest, Path, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
 use thiserror::Error;
+use time::OffsetDateTime;
+
+/// Same limit axum applies to the `Bytes` extractor by default
+pub const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Metering happens against this cache, a background task flushes it to Postgres
+pub static CALL_CACHE: LazyLock<Cache> = LazyLock::new(Cache::new);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlanState {
+    pub plan: Plan,
+    pub expires: OffsetDateTime,
+}
+
+/// Usage of one account, shared by all of its api keys
+#[derive(Debug)]
+pub struct AccountUsage {
+    pub email: String,
+    pub state: RwLock<PlanState>,
+    // compute units drawn from the plan limit and the trace quota this cycle, flushed or not
+    pub compute_units: AtomicI64,
+    pub trace_compute_units: AtomicI64,
+    // usage metered since the last flush, compute units including tracing
+    pub pending_calls: AtomicI64,
+    pub pending_compute_units: AtomicI64,
+    pub pending_trace_compute_units: AtomicI64,
+}
+
+impl AccountUsage {
+    /// compute units are taken as persisted, tracing included
+    pub fn new(
+        email: String,
+        plan: Plan,
+        expires: OffsetDateTime,
+        compute_units: i64,
+        trace_compute_units: i64,
+    ) -> Self {
+        AccountUsage {
+            email,
+            state: RwLock::new(PlanState { plan, expires }),
+            compute_units: AtomicI64::new(compute_units - trace_compute_units),
+            trace_compute_units: AtomicI64::new(trace_compute_units),
+            pending_calls: AtomicI64::new(0),
+            pending_compute_units: AtomicI64::new(0),
+            pending_trace_compute_units: AtomicI64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> PlanState {
+        *self.state.read().unwrap()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        OffsetDateTime::now_utc() > self.state().expires
+    }
+
+    /// meters a request, nothing is metered if it doesn't fit into the plan limit or trace quota
+    pub fn try_consume(&self, metered: &Metered) -> Result<(), RpcAuthErrors> {
+        let state = self.state();
+        if metered.trace_compute_units > 0 && state.plan.get_trace_limit() == 0 {
+            Err(RpcAuthErrors::TracingNotInPlan(state.plan))?
+        }
+
+        // This behavior might be a little counter intuitive, but it's good for the user.
+        // Even if the plan is expired, let the call through since it will downgrade to free if they can't pay
+        let enforced = OffsetDateTime::now_utc() <= state.expires;
+        let compute_units = metered.plan_compute_units();
+        let used = self
+            .compute_units
+            .fetch_add(compute_units, Ordering::AcqRel);
+        if enforced && used + compute_units > state.plan.get_plan_limit() as i64 {
+            self.compute_units
+                .fetch_sub(compute_units, Ordering::AcqRel);
+            Err(RpcAuthErrors::OutOfCredits)?
+        }
+
+        let traced = self
+            .trace_compute_units
+            .fetch_add(metered.trace_compute_units, Ordering::AcqRel);
+        if enforced
+            && metered.trace_compute_units > 0
+            && traced + metered.trace_compute_units > state.plan.get_trace_limit() as i64
+        {
+            self.trace_compute_units
+                .fetch_sub(metered.trace_compute_units, Ordering::AcqRel);
+            self.compute_units
+                .fetch_sub(compute_units, Ordering::AcqRel);
+            Err(RpcAuthErrors::OutOfTraceQuota)?
+        }
+
+        self.restore_pending(*metered);
+        Ok(())
+    }
+
+    /// resets the pending counters, returns the usage metered since the last flush
+    pub fn take_pending(&self) -> Metered {
+        Metered {
+            calls: self.pending_calls.swap(0, Ordering::AcqRel),
+            compute_units: self.pending_compute_units.swap(0, Ordering::AcqRel),
+            trace_compute_units: self.pending_trace_compute_units.swap(0, Ordering::AcqRel),
+        }
+    }
+
+    /// starts a new cycle on another plan, usage that wasn't flushed yet was billed already
+    pub fn reset(&self, plan: Plan) {
+        self.state.write().unwrap().plan = plan;
+        for counter in [
+            &self.compute_units,
+            &self.trace_compute_units,
+            &self.pending_calls,
+            &self.pending_compute_units,
+            &self.pending_trace_compute_units,
+        ] {
+            counter.store(0, Ordering::Release);
+        }
+    }
+
+    /// adds usage that still has to be flushed
+    pub fn restore_pending(&self, metered: Metered) {
+        self.pending_calls
+            .fetch_add(metered.calls, Ordering::AcqRel);
+        self.pending_compute_units
+            .fetch_add(metered.compute_units, Ordering::AcqRel);
+        self.pending_trace_compute_units
+            .fetch_add(metered.trace_compute_units, Ordering::AcqRel);
+    }
+}
+
+/// Usage of one api key and the keys it was rotated from, counted on top of the usage
+/// of its account
+#[derive(Debug, Default)]
+pub struct KeyUsage {
+    // calls made this cycle, flushed or not
+    pub calls: AtomicI64,
+    // monthly calls the lineage may make on top of the plan limit of the account, 0 if uncapped
+    cap: AtomicI64,
+    // usage metered since the last flush
+    pub pending_calls: AtomicI64,
+    pub pending_compute_units: AtomicI64,
+}
+
+impl KeyUsage {
+    pub fn new(calls: i64, cap: Option<i64>) -> Self {
+        KeyUsage {
+            calls: AtomicI64::new(calls),
+            cap: AtomicI64::new(cap.unwrap_or(0)),
+            ..Default::default()
+        }
+    }
+
+    pub fn cap(&self) -> Option<i64> {
+        Some(self.cap.load(Ordering::Acquire)).filter(|cap| *cap > 0)
+    }
+
+    pub fn set_cap(&self, cap: Option<i64>) {
+        self.cap.store(cap.unwrap_or(0), Ordering::Release);
+    }
+
+    /// reserves the calls of a request, nothing is reserved if they don't fit into the cap
+    pub fn try_reserve(&self, calls: i64) -> Result<(), RpcAuthErrors> {
+        let used = self.calls.fetch_add(calls, Ordering::AcqRel);
+        if let Some(cap) = self.cap()
+            && used + calls > cap
+        {
+            self.calls.fetch_sub(calls, Ordering::AcqRel);
+            Err(RpcAuthErrors::KeyCapReached)?
+        }
+        Ok(())
+    }
+
+    /// gives back calls reserved for a request the account couldn't pay for
+    pub fn release(&self, calls: i64) {
+        self.calls.fetch_sub(calls, Ordering::AcqRel);
+    }
+
+    /// resets the pending counters, returns the calls and compute units metered since the last flush
+    pub fn take_pending(&self) -> (i64, i64) {
+        (
+            self.pending_calls.swap(0, Ordering::AcqRel),
+            self.pending_compute_units.swap(0, Ordering::AcqRel),
+        )
+    }
+
+    /// starts a new cycle, like the account the key belongs to
+    pub fn reset(&self) {
+        self.calls.store(0, Ordering::Release);
+        self.pending_calls.store(0, Ordering::Release);
+        self.pending_compute_units.store(0, Ordering::Release);
+    }
+
+    /// adds usage that still has to be flushed
+    pub fn restore_pending(&self, calls: i64, compute_units: i64) {
+        self.pending_calls.fetch_add(calls, Ordering::AcqRel);
+        self.pending_compute_units
+            .fetch_add(compute_units, Ordering::AcqRel);
+    }
+}
+
+/// An api key known to the cache, requests are verified against its hash
+#[derive(Debug, Clone)]
+pub struct CachedKey {
+    pub hash: ApiKeyHash,
+    pub expires: Option<OffsetDateTime>,
+    pub scope: Arc<KeyScope>,
+    // keys rotated from one another share a lineage and a rate limit
+    pub lineage: Arc<str>,
+    // unix time of the last request since the last flush, 0 if it wasn't used
+    pub last_used: Arc<AtomicI64>,
+    // calls and cap of the lineage
+    pub key_usage: Arc<KeyUsage>,
+    pub usage: Arc<AccountUsage>,
+}
+
+impl CachedKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| OffsetDateTime::now_utc() > expires)
+    }
+
+    /// records a request, a single store so it's cheap enough for every call
+    pub fn touch(&self) {
+        self.last_used.store(
+            OffsetDateTime::now_utc().unix_timestamp(),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// meters a request against the cap of the key and the limits of its account
+    pub fn try_consume(&self, metered: &Metered) -> Result<(), RpcAuthErrors> {
+        self.key_usage.try_reserve(metered.calls)?;
+        if let Err(e) = self.usage.try_consume(metered) {
+            self.key_usage.release(metered.calls);
+            Err(e)?
+        }
+        self.key_usage
+            .restore_pending(metered.calls, metered.compute_units);
+        Ok(())
+    }
+}
+
+/// An api key as loaded from Postgres
+pub struct CachedKeyRow {
+    pub prefix: String,
+    pub hash: ApiKeyHash,
+    pub expires: Option<OffsetDateTime>,
+    pub scope: KeyScope,
+    pub lineage: String,
+    pub cap: Option<i64>,
+    // calls of the key this cycle
+    pub calls: i64,
+    pub usage: AccountUsage,
+}
 
 pub struct Cache {
-    // api_key => calls
-    pub entries: Arc<RwLock<HashMap<String, Arc<AtomicU64>>>>,
+    // key prefix => the key and usage of the account owning it
+    pub entries: Arc<RwLock<HashMap<String, CachedKey>>>,
 }
 
 impl Default for Cache {
@@ -30,51 +303,331 @@ impl Cache {
         }
     }
 
-    /// returns old value before incrementing or inserting
-    pub fn fetch_incr_or_insert(&self, key: String) -> u64 {
-        match self.count_ref(&key) {
-            Some(e) => e.fetch_add(1, Ordering::Acquire),
-            None => self.insert(key),
-        }
-    }
-
-    pub fn insert(&self, key: String) -> u64 {
+    /// returns the entry that ended up in the cache for the key
+    pub fn insert(&self, row: CachedKeyRow) -> CachedKey {
+        let CachedKeyRow {
+            prefix,
+            hash,
+            expires,
+            scope,
+            lineage,
+            cap,
+            calls,
+            usage,
+        } = row;
         let mut hm = self.entries.write().unwrap();
         // catch all in case multiple requests are in flight and cache isn't populated
-        match hm.get_mut(&key) {
-            Some(e) => e.fetch_add(1, Ordering::Acquire),
-            None => {
-                hm.insert(key, Arc::new(AtomicU64::new(1)));
-                1
+        if let Some(e) = hm.get(&prefix) {
+            return e.clone();
+        }
+        // keys of the same account draw from the same counters
+        let usage = hm
+            .values()
+            .find(|e| e.usage.email == usage.email)
+            .map(|e| e.usage.clone())
+            .unwrap_or_else(|| Arc::new(usage));
+        // keys of the same lineage share their cap, each of them counts the calls of all of them
+        let key_usage = match hm.values().find(|e| *e.lineage == *lineage) {
+            Some(e) => {
+                e.key_usage.calls.fetch_max(calls, Ordering::AcqRel);
+                e.key_usage.set_cap(cap);
+                e.key_usage.clone()
             }
+            None => Arc::new(KeyUsage::new(calls, cap)),
+        };
+        let entry = CachedKey {
+            hash,
+            expires,
+            scope: Arc::new(scope),
+            lineage: lineage.into(),
+            last_used: Arc::new(AtomicI64::new(0)),
+            key_usage,
+            usage,
+        };
+        hm.insert(prefix, entry.clone());
+        entry
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.entries.write().unwrap().remove(key);
+    }
+
+    /// changes a cached key in place, unlike removing it this keeps usage that wasn't flushed yet
+    pub fn update(&self, prefix: &str, f: impl FnOnce(&mut CachedKey)) {
+        if let Some(e) = self.entries.write().unwrap().get_mut(prefix) {
+            f(e);
+        }
+    }
+
+    /// moves an account and its keys to a new plan with fresh counters
+    pub fn reset_account(&self, email: &str, plan: Plan) {
+        let hm = self.entries.read().unwrap();
+        for e in hm.values().filter(|e| e.usage.email == email) {
+            e.key_usage.reset();
+        }
+        if let Some(e) = hm.values().find(|e| e.usage.email == email) {
+            e.usage.reset(plan);
+        }
+    }
+
+    /// changes the cap shared by the keys of a lineage
+    pub fn set_lineage_cap(&self, lineage: &str, cap: Option<i64>) {
+        let hm = self.entries.read().unwrap();
+        if let Some(e) = hm.values().find(|e| *e.lineage == *lineage) {
+            e.key_usage.set_cap(cap);
         }
     }
 
     /// CRITICAL: THIS DOES NOT CONTEND RW LOCK AS WRITER
-    pub fn count_ref(&self, key: &str) -> Option<Arc<AtomicU64>> {
+    pub fn count_ref(&self, prefix: &str) -> Option<CachedKey> {
         // operate on a value without holding onto the lock
         // drops at the end of the scope
         let hm = self.entries.read().unwrap();
-        let key = hm.get(key);
+        let key = hm.get(prefix);
         key.cloned()
     }
 
-    pub fn refresh_entire_cache(&self, new_hm: HashMap<String, Arc<AtomicU64>>) {
+    /// resets the last use of every key, returns the keys used since the last flush
+    pub fn take_last_used(&self) -> Vec<(String, i64)> {
+        let hm = self.entries.read().unwrap();
+        hm.iter()
+            .filter_map(|(prefix, e)| {
+                let used = e.last_used.swap(0, Ordering::AcqRel);
+                (used > 0).then(|| (prefix.clone(), used))
+            })
+            .collect()
+    }
+
+    /// puts back a last use that failed to flush, unless the key was used again since
+    pub fn restore_last_used(&self, prefix: &str, used: i64) {
+        if let Some(e) = self.entries.read().unwrap().get(prefix) {
+            let _ = e
+                .last_used
+                .compare_exchange(0, used, Ordering::AcqRel, Ordering::Acquire);
+        }
+    }
+
+    /// usage of every lineage in the cache once, regardless of how many keys it has
+    pub fn key_usage(&self) -> Vec<(String, Arc<KeyUsage>)> {
+        let hm = self.entries.read().unwrap();
+        let mut lineages: HashMap<&str, Arc<KeyUsage>> = HashMap::new();
+        for CachedKey {
+            lineage, key_usage, ..
+        } in hm.values()
+        {
+            lineages
+                .entry(lineage.as_ref())
+                .or_insert_with(|| key_usage.clone());
+        }
+        lineages
+            .into_iter()
+            .map(|(lineage, usage)| (lineage.to_string(), usage))
+            .collect()
+    }
+
+    /// every account in the cache once, regardless of how many keys point to it
+    pub fn accounts(&self) -> Vec<Arc<AccountUsage>> {
+        let hm = self.entries.read().unwrap();
+        let mut accounts: HashMap<&str, Arc<AccountUsage>> = HashMap::new();
+        for CachedKey { usage, .. } in hm.values() {
+            accounts
+                .entry(usage.email.as_str())
+                .or_insert_with(|| usage.clone());
+        }
+        accounts.into_values().collect()
+    }
+
+    /// replaces the cache with the state loaded from Postgres.
+    /// Existing entries are updated in place so in-flight requests keep metering into them,
+    /// usage that wasn't flushed yet is added on top of the persisted compute units.
+    pub fn refresh_entire_cache(&self, rows: Vec<CachedKeyRow>) {
+        let last_used: HashMap<String, Arc<AtomicI64>> = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(prefix, e)| (prefix.clone(), e.last_used.clone()))
+            .collect();
+        let existing_lineages: HashMap<String, Arc<KeyUsage>> =
+            self.key_usage().into_iter().collect();
+        // each key of a lineage counts the calls of all of them, the highest count is the latest
+        let mut lineage_calls: HashMap<String, i64> = HashMap::new();
+        for row in &rows {
+            let calls = lineage_calls.entry(row.lineage.clone()).or_default();
+            *calls = (*calls).max(row.calls);
+        }
+        let existing: HashMap<String, Arc<AccountUsage>> = self
+            .accounts()
+            .into_iter()
+            .map(|usage| (usage.email.clone(), usage))
+            .collect();
+
+        let mut accounts: HashMap<String, Arc<AccountUsage>> = HashMap::new();
+        let mut lineages: HashMap<String, Arc<KeyUsage>> = HashMap::new();
+        let mut new_hm = HashMap::with_capacity(rows.len());
+        for CachedKeyRow {
+            prefix,
+            hash,
+            expires,
+            scope,
+            lineage,
+            cap,
+            calls: _,
+            usage,
+        } in rows
+        {
+            let entry = accounts.entry(usage.email.clone()).or_insert_with(|| {
+                match existing.get(&usage.email) {
+                    Some(e) => {
+                        *e.state.write().unwrap() = usage.state();
+                        let pending_trace = e.pending_trace_compute_units.load(Ordering::Acquire);
+                        let pending =
+                            e.pending_compute_units.load(Ordering::Acquire) - pending_trace;
+                        e.compute_units.store(
+                            usage.compute_units.load(Ordering::Acquire) + pending,
+                            Ordering::Release,
+                        );
+                        e.trace_compute_units.store(
+                            usage.trace_compute_units.load(Ordering::Acquire) + pending_trace,
+                            Ordering::Release,
+                        );
+                        e.clone()
+                    }
+                    None => Arc::new(usage),
+                }
+            });
+            let key_usage = lineages.entry(lineage.clone()).or_insert_with(|| {
+                let calls = lineage_calls[&lineage];
+                match existing_lineages.get(&lineage) {
+                    Some(key_usage) => {
+                        let pending = key_usage.pending_calls.load(Ordering::Acquire);
+                        key_usage.calls.store(calls + pending, Ordering::Release);
+                        key_usage.set_cap(cap);
+                        key_usage.clone()
+                    }
+                    None => Arc::new(KeyUsage::new(calls, cap)),
+                }
+            });
+            let last_used = last_used.get(&prefix).cloned().unwrap_or_default();
+            new_hm.insert(
+                prefix,
+                CachedKey {
+                    hash,
+                    expires,
+                    scope: Arc::new(scope),
+                    lineage: lineage.into(),
+                    last_used,
+                    key_usage: key_usage.clone(),
+                    usage: entry.clone(),
+                },
+            );
+        }
+
         let mut lock = self.entries.write().unwrap();
         *lock = new_hm;
     }
 }
 
 pub async fn route_call(
-    Path(route_info): Path<[String; 2]>,
-    body: Bytes,
-) -> Result<impl IntoResponse, RouterErrors> {
+    Path(route_info): Path<HashMap<String, String>>,
+    scope: Option<Extension<Arc<KeyScope>>>,
+    source: Option<Extension<UsageSource>>,
+    payload: JsonRpcPayload,
+) -> Result<Response, RpcError> {
+    let ids = RequestIds::from(&payload);
     let raw_destination = route_info
-        .first()
-        .ok_or_else(|| RouterErrors::DestinationError)?;
-    let dest = raw_destination.parse::<PoktChains>()?;
-    let result = dest.relay_transaction(body).await?;
-    Ok((StatusCode::OK, result))
+        .get("chain")
+        .ok_or_else(|| RouterErrors::DestinationError)
+        .with_ids(&ids)?;
+    let dest = raw_destination.parse::<PoktChains>().with_ids(&ids)?;
+
+    // methods the chain does not serve or the key may not call are answered here instead of by the node
+    let scope = scope.as_ref().map(|Extension(scope)| scope.as_ref());
+    let (payload, denied) = apply_policy(dest, scope, payload);
+    let Some(payload) = payload else {
+        return Ok(match denied.as_slice() {
+            // only notifications were denied, they get no answer
+            [] => StatusCode::NO_CONTENT.into_response(),
+            [error] => Json(error).into_response(),
+            errors => Json(errors).into_response(),
+        });
+    };
+
+    let result = dest.relay_transaction(&payload).await;
+    if let Some(Extension(source)) = &source {
+        let status = match result {
+            Ok(_) => CallStatus::Success,
+            Err(_) => CallStatus::UpstreamError,
+        };
+        USAGE_ROLLUP.record(source, dest, &payload, status);
+    }
+    let result = result.with_ids(&ids)?;
+    if denied.is_empty() {
+        return Ok((StatusCode::OK, result).into_response());
+    }
+
+    // only batches are split, their responses are merged with the errors
+    let bytes = axum::body::to_bytes(result, usize::MAX)
+        .await
+        .map_err(|_| RouterErrors::UpstreamResponse)
+        .with_ids(&ids)?;
+    // notifications aren't answered, if nothing else was relayed the errors are the response
+    let mut responses: Vec<Value> = if payload.requests().iter().all(|call| call.id.is_none()) {
+        vec![]
+    } else {
+        serde_json::from_slice(&bytes)
+            .map_err(|_| RouterErrors::UpstreamResponse)
+            .with_ids(&ids)?
+    };
+    for error in denied {
+        responses.push(
+            serde_json::to_value(error)
+                .map_err(|_| RouterErrors::UpstreamResponse)
+                .with_ids(&ids)?,
+        );
+    }
+    Ok(Json(responses).into_response())
+}
+
+/// parses a request body into a single call or a batch of well-formed JSON-RPC 2.0 calls,
+/// rejecting empty and oversized batches
+pub fn parse_payload(body: &[u8]) -> Result<JsonRpcPayload, RouterErrors> {
+    let body: Value = serde_json::from_slice(body).map_err(|_| RouterErrors::InvalidJson)?;
+    let payload: JsonRpcPayload =
+        serde_json::from_value(body).map_err(|_| RouterErrors::NotJsonRpc)?;
+
+    if payload.is_empty() {
+        Err(RouterErrors::EmptyBatch)?
+    }
+
+    if !payload.requests().iter().all(JsonRpcRequest::is_valid) {
+        Err(RouterErrors::NotJsonRpc)?
+    }
+
+    if payload.len() > *MAX_BATCH_SIZE {
+        Err(RouterErrors::BatchTooLarge(*MAX_BATCH_SIZE))?
+    }
+
+    Ok(payload)
+}
+
+/// The metering middleware parses the body before the handler runs and leaves the payload
+/// in the request extensions, so it is only parsed again when no middleware is present.
+impl<S> FromRequest<S> for JsonRpcPayload
+where
+    S: Send + Sync,
+{
+    type Rejection = RouterErrors;
+
+    async fn from_request(mut req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(payload) = req.extensions_mut().remove::<JsonRpcPayload>() {
+            return Ok(payload);
+        }
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|_| RouterErrors::NotJsonRpc)?;
+        parse_payload(&body)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -83,35 +636,377 @@ pub enum RouterErrors {
     DestinationError,
     #[error(transparent)]
     Relay(#[from] RelayErrors),
-    #[error("malformed payload")]
+    #[error("Parse error")]
+    InvalidJson,
+    #[error("Invalid JSON-RPC 2.0 request")]
     NotJsonRpc,
+    #[error("Batch must contain at least one call")]
+    EmptyBatch,
+    #[error("Batch exceeds the maximum of {0} calls")]
+    BatchTooLarge(usize),
+    #[error("Request body exceeds {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("Failed to read the upstream response")]
+    UpstreamResponse,
+}
+
+impl JsonRpcFailure for RouterErrors {
+    fn code(&self) -> i64 {
+        match self {
+            RouterErrors::Relay(e) => e.code(),
+            RouterErrors::InvalidJson => PARSE_ERROR,
+            RouterErrors::DestinationError
+            | RouterErrors::NotJsonRpc
+            | RouterErrors::EmptyBatch
+            | RouterErrors::BatchTooLarge(_)
+            | RouterErrors::BodyTooLarge(_) => INVALID_REQUEST,
+            RouterErrors::UpstreamResponse => UPSTREAM_ERROR,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RouterErrors::Relay(e) => e.status(),
+            RouterErrors::DestinationError
+            | RouterErrors::InvalidJson
+            | RouterErrors::NotJsonRpc
+            | RouterErrors::EmptyBatch => StatusCode::BAD_REQUEST,
+            RouterErrors::BatchTooLarge(_) | RouterErrors::BodyTooLarge(_) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            RouterErrors::UpstreamResponse => StatusCode::BAD_GATEWAY,
+        }
+    }
 }
 
 impl IntoResponse for RouterErrors {
-    fn into_response(self) -> axum::response::Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    fn into_response(self) -> Response {
+        RpcError::new(self, RequestIds::Unknown).into_response()
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use crate::routes::relayer::types::{PoktChains, Relayer};
+    use super::{AccountUsage, Cache, CachedKeyRow, RouterErrors, parse_payload};
+    use crate::{
+        database::types::Plan,
+        middleware::{compute_units::Metered, rpc_service::RpcAuthErrors},
+        routes::{
+            api_keys::{ApiKeyHash, KeyScope},
+            relayer::types::{MAX_BATCH_SIZE, PoktChains, Relayer},
+        },
+    };
     use http_body_util::BodyExt;
     use serde_json::json;
+    use std::{sync::Arc, sync::atomic::Ordering};
+    use time::{Duration, OffsetDateTime};
+
+    #[test]
+    fn keys_share_account_usage() {
+        let cache = Cache::new();
+        let expires = OffsetDateTime::now_utc() + Duration::days(1);
+        let limit = Plan::Free.get_plan_limit() as i64;
+        let a = cache.insert(CachedKeyRow {
+            prefix: "key-a".to_string(),
+            hash: ApiKeyHash::new("key-a"),
+            expires: None,
+            scope: KeyScope::default(),
+            lineage: "key-a".to_string(),
+            cap: None,
+            calls: 0,
+            usage: AccountUsage::new(
+                "abc@aol.com".to_string(),
+                Plan::Free,
+                expires,
+                limit - 10,
+                0,
+            ),
+        });
+        let b = cache.insert(CachedKeyRow {
+            prefix: "key-b".to_string(),
+            hash: ApiKeyHash::new("key-b"),
+            expires: Some(expires),
+            scope: KeyScope::default(),
+            lineage: "key-a".to_string(),
+            cap: None,
+            calls: 0,
+            usage: AccountUsage::new("abc@aol.com".to_string(), Plan::Free, expires, 0, 0),
+        });
+        assert!(Arc::ptr_eq(&a.usage, &b.usage));
+        assert!(b.hash.verifies("key-b") && !b.hash.verifies("key-a"));
+        assert!(!b.is_expired());
+
+        // last use is tracked per key
+        b.touch();
+        assert_eq!(cache.take_last_used().len(), 1);
+        assert!(cache.take_last_used().is_empty());
+        let (a, b) = (a.usage, b.usage);
+        assert_eq!(cache.accounts().len(), 1);
+
+        let metered = |calls, compute_units| Metered {
+            calls,
+            compute_units,
+            trace_compute_units: 0,
+        };
+        assert!(a.try_consume(&metered(1, 6)).is_ok());
+        // would exceed the plan limit, nothing gets metered
+        assert!(b.try_consume(&metered(1, 6)).is_err());
+        assert!(b.try_consume(&metered(2, 4)).is_ok());
+        assert_eq!(a.compute_units.load(Ordering::Acquire), limit);
+        assert_eq!(a.take_pending(), metered(3, 10));
+        assert!(a.take_pending().is_empty());
+    }
+
+    #[test]
+    fn keys_are_capped_within_the_account() {
+        let cache = Cache::new();
+        let expires = OffsetDateTime::now_utc() + Duration::days(1);
+        let limit = Plan::Free.get_plan_limit() as i64;
+        let row = |prefix: &str, cap, calls, compute_units| CachedKeyRow {
+            prefix: prefix.to_string(),
+            hash: ApiKeyHash::new(prefix),
+            expires: None,
+            scope: KeyScope::default(),
+            lineage: prefix.to_string(),
+            cap,
+            calls,
+            usage: AccountUsage::new(
+                "abc@aol.com".to_string(),
+                Plan::Free,
+                expires,
+                compute_units,
+                0,
+            ),
+        };
+        let staging = cache.insert(row("staging", Some(3), 1, limit - 10));
+        let production = cache.insert(row("production", None, 0, 0));
+        let metered = |calls| Metered {
+            calls,
+            compute_units: 1,
+            trace_compute_units: 0,
+        };
+
+        assert!(staging.try_consume(&metered(2)).is_ok());
+        assert!(matches!(
+            staging.try_consume(&metered(1)),
+            Err(RpcAuthErrors::KeyCapReached)
+        ));
+        // the rest of the plan stays with the other keys
+        assert!(production.try_consume(&metered(1)).is_ok());
+        assert_eq!(staging.key_usage.take_pending(), (2, 1));
+        assert_eq!(production.key_usage.take_pending(), (1, 1));
+
+        // a call the account can't pay for doesn't count against the cap
+        cache.set_lineage_cap("staging", Some(10));
+        let staging = cache.count_ref("staging").unwrap();
+        let too_expensive = Metered {
+            calls: 1,
+            compute_units: 100,
+            trace_compute_units: 0,
+        };
+        assert!(matches!(
+            staging.try_consume(&too_expensive),
+            Err(RpcAuthErrors::OutOfCredits)
+        ));
+        assert_eq!(staging.key_usage.calls.load(Ordering::Acquire), 3);
+    }
+
+    #[test]
+    fn rotated_keys_share_their_cap() {
+        let cache = Cache::new();
+        let expires = OffsetDateTime::now_utc() + Duration::days(1);
+        let row = |prefix: &str, calls| CachedKeyRow {
+            prefix: prefix.to_string(),
+            hash: ApiKeyHash::new(prefix),
+            expires: None,
+            scope: KeyScope::default(),
+            lineage: "old".to_string(),
+            cap: Some(5),
+            calls,
+            usage: AccountUsage::new("abc@aol.com".to_string(), Plan::Free, expires, 0, 0),
+        };
+        let metered = Metered {
+            calls: 1,
+            compute_units: 1,
+            trace_compute_units: 0,
+        };
+        // the successor took over the calls of the old key
+        let old = cache.insert(row("old", 3));
+        let new = cache.insert(row("new", 4));
+        assert!(Arc::ptr_eq(&old.key_usage, &new.key_usage));
+        assert!(old.try_consume(&metered).is_ok());
+        assert!(matches!(
+            new.try_consume(&metered),
+            Err(RpcAuthErrors::KeyCapReached)
+        ));
+        assert_eq!(cache.key_usage().len(), 1);
+
+        cache.refresh_entire_cache(vec![row("old", 3), row("new", 4)]);
+        let new = cache.count_ref("new").unwrap();
+        assert!(Arc::ptr_eq(
+            &cache.count_ref("old").unwrap().key_usage,
+            &new.key_usage
+        ));
+        // usage that wasn't flushed yet stays counted
+        assert_eq!(new.key_usage.calls.load(Ordering::Acquire), 5);
+
+        // raising the cap of the lineage lifts it for both keys
+        cache.set_lineage_cap("old", Some(10));
+        assert!(new.try_consume(&metered).is_ok());
+        assert_eq!(old.key_usage.cap(), Some(10));
+    }
+
+    #[test]
+    fn upgrades_start_a_fresh_cycle() {
+        let cache = Cache::new();
+        let expires = OffsetDateTime::now_utc() + Duration::days(1);
+        let limit = Plan::Free.get_plan_limit() as i64;
+        let key = cache.insert(CachedKeyRow {
+            prefix: "upgraded".to_string(),
+            hash: ApiKeyHash::new("upgraded"),
+            expires: None,
+            scope: KeyScope::default(),
+            lineage: "upgraded".to_string(),
+            cap: None,
+            calls: 4,
+            usage: AccountUsage::new("abc@aol.com".to_string(), Plan::Free, expires, limit, 0),
+        });
+        let metered = Metered {
+            calls: 1,
+            compute_units: 1,
+            trace_compute_units: 0,
+        };
+        assert!(matches!(
+            key.try_consume(&metered),
+            Err(RpcAuthErrors::OutOfCredits)
+        ));
+
+        cache.reset_account("abc@aol.com", Plan::Tier1);
+        assert_eq!(key.usage.state().plan, Plan::Tier1);
+        assert!(key.try_consume(&metered).is_ok());
+        assert_eq!(key.usage.take_pending(), metered);
+        assert_eq!(key.key_usage.take_pending(), (1, 1));
+        assert_eq!(key.key_usage.calls.load(Ordering::Acquire), 1);
+    }
+
+    #[test]
+    fn tracing_needs_a_paid_plan() {
+        let expires = OffsetDateTime::now_utc() + Duration::days(1);
+        let trace = Metered {
+            calls: 1,
+            compute_units: 100,
+            trace_compute_units: 100,
+        };
+        let free = AccountUsage::new("abc@aol.com".to_string(), Plan::Free, expires, 0, 0);
+        assert!(matches!(
+            free.try_consume(&trace),
+            Err(RpcAuthErrors::TracingNotInPlan(Plan::Free))
+        ));
+
+        let quota = Plan::Tier1.get_trace_limit() as i64;
+        let paid = AccountUsage::new(
+            "abc@aol.com".to_string(),
+            Plan::Tier1,
+            expires,
+            quota - 50,
+            quota - 50,
+        );
+        assert!(matches!(
+            paid.try_consume(&trace),
+            Err(RpcAuthErrors::OutOfTraceQuota)
+        ));
+        // the rejected call doesn't count against the plan limit either
+        assert_eq!(paid.compute_units.load(Ordering::Acquire), 0);
+        assert!(paid.take_pending().is_empty());
+
+        // traces are paid from the quota alone
+        let trace = Metered {
+            calls: 2,
+            compute_units: 60,
+            trace_compute_units: 50,
+        };
+        assert!(paid.try_consume(&trace).is_ok());
+        assert_eq!(paid.compute_units.load(Ordering::Acquire), 10);
+        assert_eq!(paid.trace_compute_units.load(Ordering::Acquire), quota);
+        assert_eq!(paid.take_pending(), trace);
+    }
+
+    #[test]
+    fn parses_single_and_batch() {
+        let single = json!({"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id": 1});
+        let payload = parse_payload(single.to_string().as_bytes()).unwrap();
+        assert!(!payload.is_batch());
+        assert_eq!(payload.len(), 1);
+
+        let batch = json!([
+            {"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id": 1},
+            {"jsonrpc":"2.0","method":"eth_chainId","id": 2},
+            {"jsonrpc":"2.0","method":"eth_gasPrice","params":[],"id": "three"}
+        ]);
+        let payload = parse_payload(batch.to_string().as_bytes()).unwrap();
+        assert!(payload.is_batch());
+        assert_eq!(payload.len(), 3);
+
+        // a null id is relayed as null, a missing one stays missing
+        let batch = json!([
+            {"jsonrpc":"2.0","method":"eth_chainId","id": null},
+            {"jsonrpc":"2.0","method":"eth_chainId"}
+        ]);
+        let payload = parse_payload(batch.to_string().as_bytes()).unwrap();
+        let relayed = serde_json::to_value(&payload).unwrap();
+        assert!(relayed[0]["id"].is_null());
+        assert!(relayed[0].get("id").is_some());
+        assert!(relayed[1].get("id").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_batches() {
+        assert!(matches!(
+            parse_payload(b"[]"),
+            Err(RouterErrors::EmptyBatch)
+        ));
+        assert!(matches!(
+            parse_payload(b"not json"),
+            Err(RouterErrors::InvalidJson)
+        ));
+        // every element must be a call
+        let batch = json!([{"jsonrpc":"2.0","method":"eth_chainId","id": 1}, 5]);
+        assert!(matches!(
+            parse_payload(batch.to_string().as_bytes()),
+            Err(RouterErrors::NotJsonRpc)
+        ));
+        // and a JSON-RPC 2.0 call
+        for call in [
+            json!({"jsonrpc":"1.0","method":"eth_chainId","id": 1}),
+            json!({"jsonrpc":"2.0","method":"","id": 1}),
+            json!({"jsonrpc":"2.0","method":"eth_chainId","params":"0x1","id": 1}),
+            json!({"jsonrpc":"2.0","method":"eth_chainId","id": [1]}),
+        ] {
+            assert!(matches!(
+                parse_payload(call.to_string().as_bytes()),
+                Err(RouterErrors::NotJsonRpc)
+            ));
+        }
+
+        let call = json!({"jsonrpc":"2.0","method":"eth_chainId","id": 1});
+        let batch = serde_json::Value::Array(vec![call; *MAX_BATCH_SIZE + 1]);
+        assert!(matches!(
+            parse_payload(batch.to_string().as_bytes()),
+            Err(RouterErrors::BatchTooLarge(_))
+        ));
+    }
 
     #[tokio::test]
     async fn relay_test() {
         let body = json!({
             "jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id": 1
         })
-        .to_string()
-        .leak()
-        .as_bytes();
+        .to_string();
 
-        let bytes = axum::body::Bytes::from_static(body);
+        let payload = parse_payload(body.as_bytes()).unwrap();
         let chain = "anvil";
         let dest = chain.parse::<PoktChains>().unwrap();
-        let res = dest.relay_transaction(bytes).await;
+        let res = dest.relay_transaction(&payload).await;
         assert!(res.is_ok());
         let text = res.unwrap().collect().await.unwrap().to_bytes().to_vec();
         let text = String::from_utf8(text).unwrap();
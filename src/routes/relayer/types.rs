@@ -1,7 +1,14 @@
+use super::{
+    cache::RESPONSE_CACHE,
+    coalesce::{IN_FLIGHT, coalesce_key, with_id},
+    errors::{INTERNAL_ERROR, INVALID_REQUEST, JsonRpcFailure, UPSTREAM_ERROR},
+    logs::{log_chunks, relay_split},
+    upstreams,
+};
 use axum::body::{Body, Bytes};
-use http::{HeaderValue, header::CONTENT_TYPE};
-use reqwest::Client;
+use http::{HeaderValue, StatusCode};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
     fmt::{self, Display, Formatter},
     future::Future,
@@ -16,8 +23,19 @@ pub static GATEWAY_ENDPOINT: LazyLock<&'static str> = LazyLock::new(|| {
     .leak()
 });
 
+/// Upper bound on the number of calls a single JSON-RPC batch may carry
+pub static MAX_BATCH_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    dotenvy::var("MAX_BATCH_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(100)
+});
+
 pub trait Relayer {
-    fn relay_transaction(&self, body: Bytes) -> impl Future<Output = Result<Body, RelayErrors>>;
+    fn relay_transaction(
+        &self,
+        payload: &JsonRpcPayload,
+    ) -> impl Future<Output = Result<Body, RelayErrors>>;
 }
 
 impl From<PoktChains> for HeaderValue {
@@ -74,33 +92,65 @@ impl PoktChains {
     }
 }
 
+impl PoktChains {
+    pub fn all() -> Vec<PoktChains> {
+        #[allow(unused_mut)]
+        let mut chains = vec![
+            PoktChains::Base,
+            PoktChains::Eth,
+            PoktChains::ArbOne,
+            PoktChains::Solana,
+            PoktChains::Sui,
+            PoktChains::Bsc,
+            PoktChains::Poly,
+            PoktChains::Op,
+        ];
+        #[cfg(any(test, feature = "dev"))]
+        chains.push(PoktChains::Anvil);
+        chains
+    }
+}
+
 impl Relayer for PoktChains {
-    async fn relay_transaction(&self, body: Bytes) -> Result<Body, RelayErrors> {
-        if cfg!(test) {
-            let provider = dotenvy::var("SEPOLIA_PROVIDER").expect("SEPOLIA_PROVIDER not found");
-            let byte_stream = Client::new()
-                .post(&provider)
-                .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-                .body(body)
-                .send()
-                .await?
-                .error_for_status()?
-                .bytes_stream();
-            let body = Body::from_stream(byte_stream);
-            Ok(body)
-        } else {
-            let byte_stream = Client::new()
-                .post(*GATEWAY_ENDPOINT)
-                .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-                .header("target-service-id", self.id())
-                .body(body)
-                .send()
-                .await?
-                .error_for_status()?
-                .bytes_stream();
-            let body = Body::from_stream(byte_stream);
-            Ok(body)
+    async fn relay_transaction(&self, payload: &JsonRpcPayload) -> Result<Body, RelayErrors> {
+        if let Some(cached) = RESPONSE_CACHE.lookup(*self, payload) {
+            return Ok(Body::from(cached));
+        }
+
+        // wide eth_getLogs ranges are relayed in chunks the upstream accepts
+        if let JsonRpcPayload::Single(request) = payload
+            && let Some(chunks) = log_chunks(*self, request)
+        {
+            return Ok(Body::from(relay_split(*self, request, chunks).await?));
+        }
+
+        // identical reads relayed at the same time share one upstream request
+        if let JsonRpcPayload::Single(request) = payload
+            && request.is_coalescable()
+        {
+            let response = IN_FLIGHT
+                .run(coalesce_key(*self, request), async {
+                    let body = Bytes::from(serde_json::to_vec(payload)?);
+                    let bytes = upstreams::relay(*self, body, true).await?.bytes().await?;
+                    if RESPONSE_CACHE.is_cacheable(payload) {
+                        RESPONSE_CACHE.store(*self, payload, &bytes);
+                    }
+                    Ok(bytes)
+                })
+                .await?;
+            return Ok(Body::from(with_id(&response, request.id.as_ref())?));
+        }
+
+        let body = Bytes::from(serde_json::to_vec(payload)?);
+        let res = upstreams::relay(*self, body, payload.is_idempotent()).await?;
+        if !RESPONSE_CACHE.is_cacheable(payload) {
+            return Ok(Body::from_stream(res.bytes_stream()));
         }
+
+        // cacheable responses are buffered so their final results can be stored
+        let bytes = res.bytes().await?;
+        RESPONSE_CACHE.store(*self, payload, &bytes);
+        Ok(Body::from(bytes))
     }
 }
 
@@ -123,10 +173,81 @@ impl FromStr for PoktChains {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    // an explicit null id is Some(Value::Null) and still answered, only a missing id makes a notification
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Value>,
+}
+
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// A JSON-RPC body is either a single call or a batch of calls.
+/// Every element of a batch is metered as its own call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JsonRpcPayload {
+    Batch(Vec<JsonRpcRequest>),
+    Single(JsonRpcRequest),
+}
+
+impl JsonRpcPayload {
+    pub fn requests(&self) -> &[JsonRpcRequest] {
+        match self {
+            JsonRpcPayload::Batch(batch) => batch,
+            JsonRpcPayload::Single(request) => std::slice::from_ref(request),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests().is_empty()
+    }
+
+    pub fn is_batch(&self) -> bool {
+        matches!(self, JsonRpcPayload::Batch(_))
+    }
+
+    /// safe to send again if the first attempt may have reached the node
+    pub fn is_idempotent(&self) -> bool {
+        !self.requests().iter().any(JsonRpcRequest::is_write)
+    }
+}
+
+impl JsonRpcRequest {
+    /// methods that broadcast or otherwise change state on the node
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self.method.as_str(),
+            "eth_sendRawTransaction"
+                | "eth_sendTransaction"
+                | "sendTransaction"
+                | "requestAirdrop"
+                | "sui_executeTransactionBlock"
+        )
+    }
+}
+
 #[derive(Debug)]
 pub enum RelayErrors {
     PoktRelayError(reqwest::Error),
     PoktChainIdParsingError,
+    PayloadSerializationError(serde_json::Error),
+    NoUpstreamAvailable,
+    DeadlineExceeded,
 }
 
 impl From<reqwest::Error> for RelayErrors {
@@ -135,6 +256,12 @@ impl From<reqwest::Error> for RelayErrors {
     }
 }
 
+impl From<serde_json::Error> for RelayErrors {
+    fn from(value: serde_json::Error) -> Self {
+        RelayErrors::PayloadSerializationError(value)
+    }
+}
+
 impl Display for RelayErrors {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -142,6 +269,35 @@ impl Display for RelayErrors {
                 write!(f, "Failed to submit transaction or parse the response")
             }
             RelayErrors::PoktChainIdParsingError => write!(f, "Could not identify chain by id"),
+            RelayErrors::PayloadSerializationError(_) => {
+                write!(f, "Failed to serialize the JSON-RPC payload")
+            }
+            RelayErrors::NoUpstreamAvailable => write!(f, "No upstream available for chain"),
+            RelayErrors::DeadlineExceeded => {
+                write!(f, "Upstreams did not respond before the deadline")
+            }
+        }
+    }
+}
+
+impl JsonRpcFailure for RelayErrors {
+    fn code(&self) -> i64 {
+        match self {
+            RelayErrors::PoktChainIdParsingError => INVALID_REQUEST,
+            RelayErrors::PayloadSerializationError(_) => INTERNAL_ERROR,
+            RelayErrors::PoktRelayError(_)
+            | RelayErrors::NoUpstreamAvailable
+            | RelayErrors::DeadlineExceeded => UPSTREAM_ERROR,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RelayErrors::PoktChainIdParsingError => StatusCode::NOT_FOUND,
+            RelayErrors::PayloadSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RelayErrors::PoktRelayError(_) => StatusCode::BAD_GATEWAY,
+            RelayErrors::NoUpstreamAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            RelayErrors::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 }
@@ -150,7 +306,10 @@ impl std::error::Error for RelayErrors {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             RelayErrors::PoktRelayError(e) => Some(e),
-            RelayErrors::PoktChainIdParsingError => None,
+            RelayErrors::PayloadSerializationError(e) => Some(e),
+            RelayErrors::PoktChainIdParsingError
+            | RelayErrors::NoUpstreamAvailable
+            | RelayErrors::DeadlineExceeded => None,
         }
     }
 }
@@ -0,0 +1,231 @@
+use super::{
+    health::UpstreamHealth,
+    types::{GATEWAY_ENDPOINT, PoktChains, RelayErrors},
+};
+use axum::body::Bytes;
+use http::{HeaderValue, StatusCode, header::CONTENT_TYPE};
+use reqwest::{Client, Response};
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+pub static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
+
+/// Deadline for a relayed request, shared by every attempt against every upstream
+pub static RELAY_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        dotenvy::var("RELAY_TIMEOUT_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(10_000),
+    )
+});
+
+/// Upstreams of every chain. The PATH gateway always comes first, self-hosted nodes
+/// are added with a comma separated `UPSTREAMS_<CHAIN>` variable, e.g. `UPSTREAMS_ARB_ONE`.
+/// Each entry is an http url, optionally followed by `;` and the websocket url of the same node.
+pub static UPSTREAMS: LazyLock<HashMap<PoktChains, Vec<Upstream>>> = LazyLock::new(|| {
+    PoktChains::all()
+        .into_iter()
+        .map(|chain| (chain, Upstream::for_chain(chain)))
+        .collect()
+});
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub url: String,
+    pub ws_url: Option<String>,
+    // the gateway routes by service id, self-hosted nodes serve a single chain
+    pub service_id: Option<&'static str>,
+    pub health: UpstreamHealth,
+}
+
+impl Upstream {
+    pub fn new(url: String, ws_url: Option<String>, service_id: Option<&'static str>) -> Self {
+        Upstream {
+            url,
+            ws_url,
+            service_id,
+            health: UpstreamHealth::default(),
+        }
+    }
+
+    /// whether a response says the upstream can't serve calls right now rather than
+    /// rejecting this one. A self-hosted node refusing our credentials is misconfigured
+    fn is_unavailable(&self, status: StatusCode) -> bool {
+        status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || (self.service_id.is_none()
+                && matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN))
+    }
+
+    fn for_chain(chain: PoktChains) -> Vec<Upstream> {
+        let mut upstreams = vec![];
+        if cfg!(test) {
+            // only tests that relay over the network need a real node
+            let provider = dotenvy::var("SEPOLIA_PROVIDER")
+                .unwrap_or_else(|_| "http://localhost:8545".to_string());
+            upstreams.push(Upstream::new(provider, None, None));
+        } else {
+            // the gateway serves websockets on the same path
+            let ws_url = GATEWAY_ENDPOINT.replacen("http", "ws", 1);
+            upstreams.push(Upstream::new(
+                GATEWAY_ENDPOINT.to_string(),
+                Some(ws_url),
+                Some(chain.id()),
+            ));
+        }
+
+        let var = format!("UPSTREAMS_{}", chain.id().to_uppercase().replace('-', "_"));
+        if let Ok(entries) = dotenvy::var(var) {
+            upstreams.extend(
+                entries
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| match entry.split_once(';') {
+                        Some((url, ws_url)) => {
+                            Upstream::new(url.to_string(), Some(ws_url.to_string()), None)
+                        }
+                        None => Upstream::new(entry.to_string(), None, None),
+                    }),
+            );
+        }
+        upstreams
+    }
+
+    async fn post(&self, body: Bytes, timeout: Duration) -> Result<Response, reqwest::Error> {
+        let mut request = HTTP_CLIENT
+            .post(&self.url)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .timeout(timeout)
+            .body(body);
+        if let Some(service_id) = self.service_id {
+            request = request.header("target-service-id", service_id);
+        }
+        request.send().await
+    }
+}
+
+/// healthy upstreams first, fastest first. Unhealthy ones are kept as a last resort.
+pub fn select(upstreams: &[Upstream]) -> Vec<&Upstream> {
+    let mut ordered: Vec<&Upstream> = upstreams.iter().collect();
+    ordered.sort_by_key(|upstream| (!upstream.health.is_available(), upstream.health.latency()));
+    ordered
+}
+
+/// upstreams of a chain that accept websocket connections, in the order they should be tried
+pub fn select_ws(chain: PoktChains) -> Vec<&'static Upstream> {
+    UPSTREAMS
+        .get(&chain)
+        .map(|upstreams| select(upstreams))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|upstream| upstream.ws_url.is_some())
+        .collect()
+}
+
+/// Posts the body to the upstreams of a chain until one answers.
+/// Requests that never reached an upstream are always retried on the next one,
+/// timeouts and responses of an unavailable upstream only when the call is safe to repeat.
+pub async fn relay(
+    chain: PoktChains,
+    body: Bytes,
+    idempotent: bool,
+) -> Result<Response, RelayErrors> {
+    relay_until(chain, body, idempotent, Instant::now() + *RELAY_TIMEOUT).await
+}
+
+/// [`relay`] against a deadline shared with other calls
+pub async fn relay_until(
+    chain: PoktChains,
+    body: Bytes,
+    idempotent: bool,
+    deadline: Instant,
+) -> Result<Response, RelayErrors> {
+    let upstreams = UPSTREAMS
+        .get(&chain)
+        .ok_or(RelayErrors::NoUpstreamAvailable)?;
+
+    let mut last_error = RelayErrors::NoUpstreamAvailable;
+    for upstream in select(upstreams) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(RelayErrors::DeadlineExceeded);
+        }
+
+        let started = Instant::now();
+        let retry = match upstream.post(body.clone(), remaining).await {
+            Ok(res) if upstream.is_unavailable(res.status()) => {
+                upstream.health.record_failure();
+                last_error = res.error_for_status().unwrap_err().into();
+                idempotent
+            }
+            Ok(res) => {
+                upstream.health.record_success(started.elapsed());
+                return Ok(res.error_for_status()?);
+            }
+            Err(e) => {
+                upstream.health.record_failure();
+                let retry = e.is_connect() || (idempotent && e.is_timeout());
+                last_error = e.into();
+                retry
+            }
+        };
+
+        if !retry {
+            break;
+        }
+        warn!("Retrying {chain} call on the next upstream");
+    }
+
+    Err(last_error)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{Upstream, select};
+    use http::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn throttled_upstreams_are_unavailable() {
+        let gateway = Upstream::new("http://a".to_string(), None, Some("eth"));
+        let node = Upstream::new("http://b".to_string(), None, None);
+        for upstream in [&gateway, &node] {
+            assert!(upstream.is_unavailable(StatusCode::BAD_GATEWAY));
+            assert!(upstream.is_unavailable(StatusCode::TOO_MANY_REQUESTS));
+            assert!(!upstream.is_unavailable(StatusCode::BAD_REQUEST));
+        }
+        assert!(!gateway.is_unavailable(StatusCode::UNAUTHORIZED));
+        assert!(node.is_unavailable(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn unhealthy_upstreams_go_last() {
+        let upstreams = vec![
+            Upstream::new("http://a".to_string(), None, None),
+            Upstream::new("http://b".to_string(), None, None),
+            Upstream::new("http://c".to_string(), None, None),
+        ];
+        upstreams[0]
+            .health
+            .record_success(Duration::from_millis(30));
+        upstreams[1]
+            .health
+            .record_success(Duration::from_millis(10));
+        upstreams[2]
+            .health
+            .record_success(Duration::from_millis(20));
+        for _ in 0..3 {
+            upstreams[1].health.record_failure();
+        }
+        assert!(!upstreams[1].health.is_available());
+
+        let urls: Vec<&str> = select(&upstreams).iter().map(|u| u.url.as_str()).collect();
+        assert_eq!(urls, ["http://c", "http://a", "http://b"]);
+    }
+}
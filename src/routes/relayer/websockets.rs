@@ -1,198 +1,939 @@
-use crate::routes::relayer::types::PoktChains;
+use crate::{
+    database::types::RELATIONAL_DATABASE,
+    middleware::{
+        compute_units::Metered,
+        connection_limit::{CONNECTION_LIMITER, CONNECTIONS_PER_KEY},
+        rate_limit::RATE_LIMITER,
+        rpc_service::RpcAuthErrors,
+    },
+    routes::{
+        api_keys::KeyScope,
+        relayer::{
+            errors::{
+                INVALID_REQUEST, JsonRpcErrorResponse, JsonRpcFailure, LIMIT_EXCEEDED, RequestIds,
+                RpcError, UPSTREAM_ERROR,
+            },
+            hub::{Topic, client_subscription_id, forward_subscription, upstream_gone},
+            policy::apply_policy,
+            queue::{ClientQueue, FrameKind, OPEN_SESSIONS, OVERFLOW_POLICY, QUEUE_CAPACITY},
+            router::{CALL_CACHE, CachedKey, parse_payload},
+            types::{JsonRpcPayload, JsonRpcRequest, PoktChains},
+            upstreams::select_ws,
+        },
+        usage::{CallStatus, USAGE_ROLLUP, UsageSource},
+    },
+};
+use axum::body::Bytes;
 use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket};
-use axum::extract::{Path, WebSocketUpgrade};
+use axum::extract::{Extension, Path, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use http::StatusCode;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
-use tokio::{select, sync::mpsc};
+use tokio::{net::TcpStream, select, task::JoinHandle};
 use tokio_tungstenite::tungstenite::ClientRequestBuilder;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::{
-    connect_async_tls_with_config, tungstenite::Message as TungsteniteMessage,
+    MaybeTlsStream, WebSocketStream, connect_async_tls_with_config,
+    tungstenite::Message as TungsteniteMessage,
 };
-use tracing::{debug, info, warn};
+use tracing::{error, info, warn};
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    path: Path<[String; 2]>,
+    path: Path<HashMap<String, String>>,
+    scope: Option<Extension<Arc<KeyScope>>>,
+    key: Option<Extension<CachedKey>>,
+    source: Option<Extension<UsageSource>>,
 ) -> Result<axum::response::Response, WsError> {
-    let path = path
-        .first()
+    let chain = path
+        .get("chain")
         .ok_or(WsError::MissingRoute)?
         .parse::<PoktChains>()
         .map_err(|_| WsError::InvalidRoute)?;
 
+    // connections count against the key and the plan of its account for as long as they are open
+    let slot = match (&key, &source) {
+        (Some(Extension(key)), Some(Extension(source))) => Some(
+            CONNECTION_LIMITER
+                .acquire(
+                    &source.prefix,
+                    &source.email,
+                    *CONNECTIONS_PER_KEY,
+                    key.usage.state().plan.get_connection_limit(),
+                )
+                .ok_or(WsError::TooManyConnections)?,
+        ),
+        _ => None,
+    };
+
     let ws = ws.max_message_size(1024 * 1024);
     let res = ws.on_upgrade(async move |user_socket| {
-        let (user_tx, mut user_rv): (SplitSink<WebSocket, Message>, SplitStream<WebSocket>) =
-            user_socket.split();
-        // CONSIDERATION: timeout await call
-        let subscription = user_rv.next().await;
-        let Some(Ok(Message::Text(sub_info))) = subscription else {
-            warn!("Unexpected message received.");
-            return;
-        };
-        let (shutdown_tx, shutdown_rx): (
-            mpsc::UnboundedSender<Command>,
-            mpsc::UnboundedReceiver<Command>,
-        ) = mpsc::unbounded_channel();
-        let (cleanup_tx, cleanup_rx): (
-            mpsc::UnboundedSender<Command>,
-            mpsc::UnboundedReceiver<Command>,
-        ) = mpsc::unbounded_channel();
-        handle_user_msgs(cleanup_rx, user_rv, shutdown_tx).await;
-        handle_ws_conn(path, shutdown_rx, cleanup_tx, sub_info, user_tx).await;
+        let (user_tx, user_rv) = user_socket.split();
+        let outbound = Arc::new(ClientQueue::new(*QUEUE_CAPACITY, *OVERFLOW_POLICY));
+        let listed = source
+            .as_ref()
+            .map(|Extension(source)| OPEN_SESSIONS.register(source, chain, outbound.clone()));
+        spawn_writer(user_tx, outbound.clone());
+        let session = Session::new(
+            chain,
+            scope.map(|Extension(scope)| scope),
+            key.map(|Extension(key)| key),
+            source.map(|Extension(source)| source),
+            outbound,
+        );
+        session.run(user_rv).await;
+        drop((slot, listed));
     });
     Ok(res)
 }
 
-pub async fn handle_user_msgs(
-    mut cleanup_rx: mpsc::UnboundedReceiver<Command>,
-    mut user_rv: SplitStream<WebSocket>,
-    shutdown_tx: mpsc::UnboundedSender<Command>,
-) {
+/// whether a key still exists and hasn't expired, None if the database can't tell
+async fn key_is_valid(prefix: &str) -> Option<bool> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS(
+                SELECT 1 FROM Api WHERE keyPrefix = $1 AND (keyExpires IS NULL OR keyExpires > now())
+            ) AS "valid!"
+        "#,
+        prefix
+    )
+    .fetch_one(RELATIONAL_DATABASE.get()?)
+    .await
+    .ok()
+}
+
+/// writes the frames of a session to the client, so subscriptions and responses share the socket
+fn spawn_writer(mut user_tx: SplitSink<WebSocket, Message>, queue: Arc<ClientQueue>) {
     tokio::spawn(async move {
-        // max wait time?
+        while let Some(msg) = queue.pop().await {
+            let close = matches!(msg, Message::Close(_));
+            if user_tx.send(msg).await.is_err() || close {
+                break;
+            }
+        }
+        // the session sees the client is gone
+        queue.close();
+    });
+}
+
+/// A JSON-RPC session over a client websocket. Subscriptions go through the hub, every other
+/// request is relayed over a dedicated upstream socket under ids of the proxy.
+struct Session {
+    chain: PoktChains,
+    scope: Option<Arc<KeyScope>>,
+    // None in dev, requests are not metered there
+    source: Option<UsageSource>,
+    // the key as it was last resolved, stands in while it is missing from the cache
+    key: Option<CachedKey>,
+    outbound: Arc<ClientQueue>,
+    // client subscription id => the task forwarding its notifications
+    subscriptions: HashMap<String, JoinHandle<()>>,
+    upstream: Option<Upstream>,
+    next_id: u64,
+    // proxy id => the call relayed under it
+    pending: HashMap<u64, PendingCall>,
+    // proxy id of the first call of a batch => the batch
+    batches: HashMap<u64, PendingBatch>,
+    // subscriptions made over the dedicated upstream
+    upstream_subscriptions: Vec<UpstreamSubscription>,
+    opened: Instant,
+    // None until the client sent its first request
+    last_request: Option<Instant>,
+    // when a ping of the proxy went unanswered for too long
+    pong_deadline: Option<Instant>,
+    // when the lost upstream of the subscriptions is reconnected next, and which attempt it is
+    reconnect: Option<(Instant, u32)>,
+}
+
+struct Upstream {
+    tx: SplitSink<NodeSocket, TungsteniteMessage>,
+    rv: SplitStream<NodeSocket>,
+}
+
+/// A call waiting for the upstream to answer
+struct PendingCall {
+    // the id the client sent it with
+    id: Value,
+    request: JsonRpcRequest,
+    batch: Option<u64>,
+    // the client id of a subscription renewed after a reconnect, not answered to the client
+    renews: Option<Value>,
+}
+
+/// A subscription over the dedicated upstream. The client keeps the id it was first given,
+/// after a reconnect the subscription is renewed and notifications are mapped back to it.
+struct UpstreamSubscription {
+    client_id: Value,
+    upstream_id: Value,
+    // the subscribe call as the client made it
+    request: JsonRpcRequest,
+}
+
+/// A batch waiting for the upstream, answered as a whole once its response arrives
+struct PendingBatch {
+    calls: Vec<u64>,
+    // calls of the batch answered by the proxy
+    responses: Vec<Value>,
+    payload: JsonRpcPayload,
+}
+
+enum Event {
+    Client(Option<Result<Message, axum::Error>>),
+    Upstream(Option<Result<TungsteniteMessage, tokio_tungstenite::tungstenite::Error>>),
+    Heartbeat,
+    Expired(Expiry),
+    Reconnect(u32),
+}
+
+/// Why a session is closed by the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expiry {
+    NoRequest,
+    Idle,
+    // a ping went unanswered
+    Dead,
+    // the key was revoked or expired since the upgrade
+    KeyInvalid,
+}
+
+impl Expiry {
+    fn reason(&self) -> &'static str {
+        match self {
+            Expiry::NoRequest => "No request was sent in time",
+            Expiry::Idle => "Idle for too long",
+            Expiry::Dead => "Ping went unanswered",
+            Expiry::KeyInvalid => "Api key was revoked or expired",
+        }
+    }
+
+    fn close_frame(&self) -> Message {
+        let code = match self {
+            Expiry::NoRequest | Expiry::KeyInvalid => 1008,
+            Expiry::Idle => 1000,
+            Expiry::Dead => 1001,
+        };
+        Message::Close(Some(CloseFrame {
+            code,
+            reason: Utf8Bytes::from_static(self.reason()),
+        }))
+    }
+}
+
+impl Session {
+    fn new(
+        chain: PoktChains,
+        scope: Option<Arc<KeyScope>>,
+        key: Option<CachedKey>,
+        source: Option<UsageSource>,
+        outbound: Arc<ClientQueue>,
+    ) -> Self {
+        Session {
+            chain,
+            scope,
+            source,
+            key,
+            outbound,
+            subscriptions: HashMap::new(),
+            upstream: None,
+            next_id: 1,
+            pending: HashMap::new(),
+            batches: HashMap::new(),
+            upstream_subscriptions: Vec::new(),
+            opened: Instant::now(),
+            last_request: None,
+            pong_deadline: None,
+            reconnect: None,
+        }
+    }
+
+    async fn run(mut self, mut user_rv: SplitStream<WebSocket>) {
+        let mut heartbeat =
+            tokio::time::interval_at(tokio::time::Instant::now() + *PING_INTERVAL, *PING_INTERVAL);
         loop {
-            select! {
-                Some(Command::Kill) = cleanup_rx.recv() => {
-                    warn!("Exiting user msg handler");
-                    return
-                },
-                Some(Ok(msg)) = user_rv.next() => {
-                    match msg {
-                        Message::Text(_utf8_bytes) => {},
-                        Message::Binary(_bytes) => {},
-                        Message::Ping(_bytes) => shutdown_tx.send(Command::Pong).unwrap(),
-                        Message::Pong(_bytes) => shutdown_tx.send(Command::Ping).unwrap(),
-                        Message::Close(_close_frame) => {
-                            shutdown_tx.send(Command::Kill).unwrap();
-                            return;
-                        },
+            let deadline = self.deadline();
+            let reconnect = self.reconnect;
+            let event = select! {
+                msg = user_rv.next() => Event::Client(msg),
+                msg = next_upstream(&mut self.upstream) => Event::Upstream(msg),
+                _ = heartbeat.tick() => Event::Heartbeat,
+                expiry = expire(deadline) => Event::Expired(expiry),
+                attempt = retry(reconnect) => Event::Reconnect(attempt),
+                // the client went away while a frame was written to it
+                _ = self.outbound.closed() => break,
+            };
+            // any frame of the client shows it is still there
+            if let Event::Client(Some(Ok(_))) = &event {
+                self.pong_deadline = None;
+            }
+            match event {
+                Event::Client(Some(Ok(Message::Text(text)))) => {
+                    self.last_request = Some(Instant::now());
+                    if let Err(expiry) = self.handle_request(text.as_bytes()).await {
+                        self.close(expiry);
+                        break;
                     }
                 }
-                // prevents a panic
-                else => {
-                    debug!("User channel and ws channel dropped");
-                    return;
+                Event::Client(Some(Ok(Message::Binary(bytes)))) => {
+                    self.last_request = Some(Instant::now());
+                    if let Err(expiry) = self.handle_request(&bytes).await {
+                        self.close(expiry);
+                        break;
+                    }
+                }
+                Event::Client(Some(Ok(Message::Close(_))) | Some(Err(_)) | None) => break,
+                // pings of the client are answered by axum
+                Event::Client(Some(Ok(_))) => {}
+                Event::Heartbeat => {
+                    if self.pong_deadline.is_none() {
+                        let _ = self.outbound.send(Message::Ping(Bytes::new()));
+                        self.pong_deadline = Some(Instant::now() + *PONG_TIMEOUT);
+                    }
+                }
+                Event::Expired(expiry) => {
+                    self.close(expiry);
+                    break;
+                }
+                Event::Upstream(Some(Ok(TungsteniteMessage::Text(text)))) => {
+                    self.handle_upstream(&text)
+                }
+                Event::Upstream(Some(Ok(TungsteniteMessage::Binary(bytes)))) => {
+                    let _ = self.outbound.send(Message::Binary(bytes));
                 }
+                Event::Upstream(Some(Ok(TungsteniteMessage::Close(_))) | Some(Err(_)) | None) => {
+                    self.upstream_lost()
+                }
+                Event::Reconnect(attempt) => self.resubscribe(attempt).await,
+                Event::Upstream(Some(Ok(_))) => {}
             }
         }
-    });
-}
 
-#[tracing::instrument]
-pub async fn handle_ws_conn(
-    path: PoktChains,
-    mut shutdown_rx: mpsc::UnboundedReceiver<Command>,
-    cleanup_tx: mpsc::UnboundedSender<Command>,
-    sub_info: Utf8Bytes,
-    mut user_tx: SplitSink<WebSocket, Message>,
-) {
-    tokio::spawn(async move {
-        'node_reconnect: loop {
-            let request = if cfg!(feature = "dev") {
-                let url = dotenvy::var("SEPOLIA_WS").unwrap().parse().unwrap();
-                ClientRequestBuilder::new(url)
-            } else {
-                ClientRequestBuilder::new("ws://localhost:3069/v1".parse().unwrap())
-                    .with_header("Target-Service-Id", String::from(path.id()))
-            };
+        for (_, task) in self.subscriptions.drain() {
+            task.abort();
+        }
+        if let Some(mut upstream) = self.upstream.take() {
+            let _ = upstream.tx.send(TungsteniteMessage::Close(None)).await;
+        }
+        self.outbound.close();
+        let stats = self.outbound.stats();
+        if stats.dropped > 0 || stats.coalesced > 0 {
+            info!(
+                "A {} session fell behind: peak queue depth {}, {} notifications dropped, {} coalesced",
+                self.chain, stats.peak_depth, stats.dropped, stats.coalesced
+            );
+        }
+    }
 
-            let config = WebSocketConfig::default().max_message_size(Some(16 * 1024 * 1024));
-            let (node_socket, _res) =
-                match connect_async_tls_with_config(request, Some(config), false, None).await {
-                    Ok((node_socket, _res)) => (node_socket, _res),
-                    Err(e) => {
-                        tracing::error!("Failed to connect to websocket: {e}");
-                        if let Err(e) = cleanup_tx.send(Command::Kill) {
-                            debug!("Failed to clean up user channel: {e}");
-                        }
-                        break 'node_reconnect;
-                    }
+    fn close(&self, expiry: Expiry) {
+        warn!("Closing a {} session: {}", self.chain, expiry.reason());
+        let _ = self.outbound.send(expiry.close_frame());
+    }
+
+    /// when the session ends unless the client does something, and why
+    fn deadline(&self) -> Option<(Instant, Expiry)> {
+        let quiet = match self.last_request {
+            None => Some((self.opened + *FIRST_REQUEST_TIMEOUT, Expiry::NoRequest)),
+            // clients listening to subscriptions may stay quiet
+            Some(last) if !self.has_subscriptions() => Some((last + *IDLE_TIMEOUT, Expiry::Idle)),
+            Some(_) => None,
+        };
+        let dead = self.pong_deadline.map(|deadline| (deadline, Expiry::Dead));
+        quiet.into_iter().chain(dead).min_by_key(|(at, _)| *at)
+    }
+
+    fn has_subscriptions(&self) -> bool {
+        !self.upstream_subscriptions.is_empty()
+            || self.subscriptions.values().any(|task| !task.is_finished())
+    }
+
+    /// meters, checks and relays a frame of the client.
+    /// Fails if the key of the session is no longer valid
+    async fn handle_request(&mut self, frame: &[u8]) -> Result<(), Expiry> {
+        let key = self.current_key().await?;
+        let payload = match parse_payload(frame) {
+            Ok(payload) => payload,
+            Err(e) => {
+                self.send(&JsonRpcErrorResponse::new(None, e.code(), e.to_string()));
+                return Ok(());
+            }
+        };
+        if let Some(key) = &key
+            && let Err(e) = self.meter(key, &payload)
+        {
+            self.reject(&payload, e);
+            return Ok(());
+        }
+        let (payload, denied) = apply_policy(self.chain, self.scope.as_deref(), payload);
+        let Some(payload) = payload else {
+            match denied.as_slice() {
+                [] => {}
+                [error] => self.send(error),
+                errors => self.send(&errors),
+            }
+            return Ok(());
+        };
+        if let JsonRpcPayload::Single(request) = &payload
+            && self.serve_locally(request)
+        {
+            return Ok(());
+        }
+        let denied = denied
+            .iter()
+            .filter_map(|error| serde_json::to_value(error).ok())
+            .collect();
+        self.forward(payload, denied).await;
+        Ok(())
+    }
+
+    /// the key of the session as it is cached now, so revocations, rotations and scope
+    /// changes apply to open sessions. None in dev, where nothing is metered.
+    /// A key missing from the cache, e.g. while it reloads, only ends the session once
+    /// the database confirms it is gone
+    async fn current_key(&mut self) -> Result<Option<CachedKey>, Expiry> {
+        let Some(source) = &self.source else {
+            return Ok(None);
+        };
+        let key = match (CALL_CACHE.count_ref(&source.prefix), &self.key) {
+            (Some(key), _) => key,
+            (None, Some(last)) if key_is_valid(&source.prefix).await != Some(false) => last.clone(),
+            (None, _) => Err(Expiry::KeyInvalid)?,
+        };
+        if key.is_expired() {
+            Err(Expiry::KeyInvalid)?
+        }
+        self.scope = Some(key.scope.clone());
+        self.key = Some(key.clone());
+        Ok(Some(key))
+    }
+
+    /// every frame is metered like a request on `/rpc`, a batch is rate limited as one request
+    /// and only the calls the policy allows are charged
+    fn meter(&self, key: &CachedKey, payload: &JsonRpcPayload) -> Result<(), RpcAuthErrors> {
+        let plan = key.usage.state().plan;
+        if !RATE_LIMITER
+            .check(&key.lineage, plan.get_rate_limit(), 1)
+            .allowed
+        {
+            Err(RpcAuthErrors::RateLimited)?
+        }
+        let metered = Metered::of_allowed(self.chain, self.scope.as_deref(), payload);
+        if !metered.is_empty() {
+            key.try_consume(&metered)?;
+        }
+        key.touch();
+        Ok(())
+    }
+
+    /// subscriptions of the hub are opened and closed by the proxy itself
+    fn serve_locally(&mut self, request: &JsonRpcRequest) -> bool {
+        if let Some(topic) = Topic::of(self.chain, request) {
+            self.subscriptions.retain(|_, task| !task.is_finished());
+            let subscription = client_subscription_id();
+            let task = tokio::spawn(forward_subscription(
+                topic,
+                request.clone(),
+                subscription.clone(),
+                self.source.clone(),
+                self.outbound.clone(),
+            ));
+            self.subscriptions.insert(subscription, task);
+            return true;
+        }
+
+        // ids the hub doesn't know are subscriptions of the dedicated upstream
+        let unsubscribed = (request.method == "eth_unsubscribe")
+            .then(|| request.params.as_ref()?.get(0)?.as_str())
+            .flatten()
+            .and_then(|id| self.subscriptions.remove(id));
+        let Some(task) = unsubscribed else {
+            return false;
+        };
+        task.abort();
+        self.send(&json!({"jsonrpc": "2.0", "id": request.id, "result": true}));
+        self.record(
+            &JsonRpcPayload::Single(request.clone()),
+            CallStatus::Success,
+        );
+        true
+    }
+
+    /// relays a payload over the dedicated upstream, connected on first use
+    async fn forward(&mut self, payload: JsonRpcPayload, mut responses: Vec<Value>) {
+        if self.upstream.is_none() {
+            if !self.connect().await {
+                error!(
+                    "No websocket upstream of {} accepted the connection",
+                    self.chain
+                );
+                self.record(&payload, CallStatus::UpstreamError);
+                responses.extend(
+                    payload
+                        .requests()
+                        .iter()
+                        .filter_map(|request| request.id.as_ref())
+                        .map(|id| lost(id, "No upstream is available for this request")),
+                );
+                return self.answer(payload.is_batch(), responses);
+            }
+            // subscriptions waiting for a reconnect are renewed on this connection
+            if self.reconnect.take().is_some() {
+                self.renew().await;
+            }
+        }
+
+        let text = self.register(payload, responses);
+        self.send_upstream(text).await;
+    }
+
+    async fn connect(&mut self) -> bool {
+        self.upstream = connect_node(self.chain).await.map(|socket| {
+            let (tx, rv) = socket.split();
+            Upstream { tx, rv }
+        });
+        self.upstream.is_some()
+    }
+
+    async fn send_upstream(&mut self, text: String) {
+        let Some(upstream) = self.upstream.as_mut() else {
+            return;
+        };
+        // a failed send shows on the read half too, which fails the calls
+        if let Err(e) = upstream
+            .tx
+            .send(TungsteniteMessage::Text(text.into()))
+            .await
+        {
+            warn!("Failed to relay a request upstream: {e}");
+        }
+    }
+
+    /// swaps the ids of the client for ids of the proxy, so responses find their call
+    /// even if the client reuses ids. Returns the payload as it is relayed.
+    fn register(&mut self, payload: JsonRpcPayload, responses: Vec<Value>) -> String {
+        let batch = payload.is_batch().then_some(self.next_id);
+        let mut calls = Vec::new();
+        let relayed: Vec<JsonRpcRequest> = payload
+            .requests()
+            .iter()
+            .map(|request| {
+                let mut relayed = request.clone();
+                if is_unsubscribe(&request.method) {
+                    self.unsubscribe_upstream(&mut relayed);
+                }
+                if let Some(id) = relayed.id.take() {
+                    let proxy_id = self.next_id;
+                    self.next_id += 1;
+                    self.pending.insert(
+                        proxy_id,
+                        PendingCall {
+                            id,
+                            request: request.clone(),
+                            batch,
+                            renews: None,
+                        },
+                    );
+                    relayed.id = Some(proxy_id.into());
+                    calls.push(proxy_id);
+                }
+                relayed
+            })
+            .collect();
+        let text = match payload {
+            JsonRpcPayload::Single(_) => serde_json::to_string(&relayed[0]),
+            JsonRpcPayload::Batch(_) => serde_json::to_string(&relayed),
+        }
+        .unwrap_or_default();
+
+        match calls.first() {
+            Some(&first) if payload.is_batch() => {
+                let batch = PendingBatch {
+                    calls,
+                    responses,
+                    payload,
                 };
+                self.batches.insert(first, batch);
+            }
+            Some(_) => {}
+            // notifications aren't answered
+            None => {
+                self.record(&payload, CallStatus::Success);
+                self.answer(payload.is_batch(), responses);
+            }
+        }
+        text
+    }
 
-            let (mut node_tx, mut node_rv) = node_socket.split();
-
-            node_tx
-                .send(TungsteniteMessage::Text(
-                    tokio_tungstenite::tungstenite::Utf8Bytes::from(sub_info.as_str()),
-                ))
-                .await
-                .unwrap();
-
-            loop {
-                select! {
-                        Some(cmd) = shutdown_rx.recv() => {
-                            match cmd {
-                                // graceful closure
-                                Command::Kill => {
-                                    let closure = axum::extract::ws::Message::Close(
-                                    Some(
-                                        axum::extract::ws::CloseFrame {
-                                            code: 1000,
-                                            reason: axum::extract::ws::Utf8Bytes::from_static("Graceful shutdown (user sent close frame)")
-                                        }
-                                    ));
-                                    user_tx.send(closure).await.unwrap();
-                                    info!("Graceful shutdown");
-                                },
-                                Command::Pong => user_tx.send(axum::extract::ws::Message::Ping(axum::body::Bytes::new())).await.unwrap(),
-                                Command::Ping => user_tx.send(axum::extract::ws::Message::Pong(axum::body::Bytes::new())).await.unwrap(),
-                            }
+    /// answers calls with the ids the client sent them with and notifications under the
+    /// subscription id the client knows, anything else of the upstream passes through as is
+    fn handle_upstream(&mut self, text: &str) {
+        match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(responses)) => {
+                let batch = responses
+                    .iter()
+                    .find_map(|response| self.pending.get(&proxy_id(response)?)?.batch);
+                if let Some(batch) = batch {
+                    return self.finish_batch(batch, responses, CallStatus::Success);
+                }
+            }
+            Ok(mut response) => {
+                if let Some(call) = self.restore_id(&mut response) {
+                    return match call.batch {
+                        // renewals are the proxy's own calls
+                        _ if call.renews.is_some() => {}
+                        // the upstream rejected the batch as a whole
+                        Some(batch) => {
+                            self.finish_batch(batch, vec![response], CallStatus::Success)
                         }
-                        Some(Ok(msg)) = node_rv.next() => {
-                            if let Some(m) = convert(msg) {
-                                match m {
-                                    Message::Text(_) => {
-                                        if let Err(e) = user_tx.send(m).await {
-                                            node_tx.send(TungsteniteMessage::Close(None)).await.unwrap();
-                                            cleanup_tx.send(Command::Kill).unwrap();
-                                            warn!("Failed to relay msg to user from node: {e}");
-                                            return;
-                                        }
-                                    }
-                                    Message::Close(_close_frame) => {
-                                        // log node info and close frame leading to ws closure
-                                        // handle close of Node's WS connection
-                                        warn!("Lost connection to node. Reconnecting ...");
-                                        continue 'node_reconnect;
-                                    }
-                                    _ => {}
-                            }
+                        None => {
+                            self.record(&JsonRpcPayload::Single(call.request), CallStatus::Success);
+                            self.send(&response);
                         }
-                    }
+                    };
+                }
+                // notifications carry the upstream id of their subscription
+                if let Some(subscription) = response.pointer_mut("/params/subscription") {
+                    let renewed = self
+                        .upstream_subscriptions
+                        .iter()
+                        .find(|renewed| renewed.upstream_id == *subscription);
+                    let text = match renewed {
+                        Some(renewed) => {
+                            *subscription = renewed.client_id.clone();
+                            response.to_string()
+                        }
+                        None => text.to_string(),
+                    };
+                    let _ = self
+                        .outbound
+                        .push(FrameKind::Notification, Message::Text(text.into()));
+                    return;
                 }
             }
+            Err(_) => {}
         }
-        //        warn!("Exiting connection bridge");
-    });
+        let _ = self.outbound.send(Message::Text(text.into()));
+    }
+
+    fn restore_id(&mut self, response: &mut Value) -> Option<PendingCall> {
+        let call = self.pending.remove(&proxy_id(response)?)?;
+        response["id"] = call.id.clone();
+        if is_subscribe(&call.request.method) {
+            self.track_subscription(&call, response);
+        }
+        Some(call)
+    }
+
+    /// remembers subscriptions the upstream confirmed and where renewed ones went
+    fn track_subscription(&mut self, call: &PendingCall, response: &mut Value) {
+        let upstream_id = response.get("result").cloned();
+        if let Some(client_id) = &call.renews {
+            let renewed = self
+                .upstream_subscriptions
+                .iter_mut()
+                .find(|subscription| subscription.client_id == *client_id);
+            match (renewed, upstream_id) {
+                (Some(renewed), Some(upstream_id)) => renewed.upstream_id = upstream_id,
+                _ => {
+                    warn!(
+                        "The upstream of {} refused to renew a subscription",
+                        self.chain
+                    );
+                    self.upstream_subscriptions
+                        .retain(|subscription| subscription.client_id != *client_id);
+                }
+            }
+            return;
+        }
+
+        let Some(upstream_id) = upstream_id else {
+            return;
+        };
+        // ids of a new upstream may be ones the client already holds
+        let mut client_id = upstream_id.clone();
+        if self
+            .upstream_subscriptions
+            .iter()
+            .any(|s| s.client_id == client_id)
+        {
+            client_id = match &upstream_id {
+                Value::Number(_) => json!(
+                    self.upstream_subscriptions
+                        .iter()
+                        .filter_map(|s| s.client_id.as_u64())
+                        .max()
+                        .unwrap_or_default()
+                        + 1
+                ),
+                _ => json!(client_subscription_id()),
+            };
+            response["result"] = client_id.clone();
+        }
+        self.upstream_subscriptions.push(UpstreamSubscription {
+            client_id,
+            upstream_id,
+            request: call.request.clone(),
+        });
+    }
+
+    /// points an unsubscribe call of the client at the current upstream subscription
+    fn unsubscribe_upstream(&mut self, request: &mut JsonRpcRequest) {
+        let Some(id) = request.params.as_mut().and_then(|params| params.get_mut(0)) else {
+            return;
+        };
+        if let Some(i) = self
+            .upstream_subscriptions
+            .iter()
+            .position(|subscription| subscription.client_id == *id)
+        {
+            *id = self.upstream_subscriptions.remove(i).upstream_id;
+        }
+    }
+
+    fn finish_batch(&mut self, batch: u64, mut responses: Vec<Value>, status: CallStatus) {
+        let Some(batch) = self.batches.remove(&batch) else {
+            return;
+        };
+        for response in &mut responses {
+            self.restore_id(response);
+        }
+        // calls the upstream left unanswered
+        for proxy_id in &batch.calls {
+            if let Some(call) = self.pending.remove(proxy_id) {
+                responses.push(lost(&call.id, "The upstream did not answer this call"));
+            }
+        }
+        responses.extend(batch.responses);
+        self.record(&batch.payload, status);
+        self.send(&responses);
+    }
+
+    /// fails every call still waiting. The upstream is reconnected on a timer if the client
+    /// has subscriptions on it, otherwise on the next request
+    fn upstream_lost(&mut self) {
+        warn!("Lost the websocket upstream of {}", self.chain);
+        self.upstream = None;
+        let batches: Vec<u64> = self.batches.keys().copied().collect();
+        for batch in batches {
+            self.finish_batch(batch, Vec::new(), CallStatus::UpstreamError);
+        }
+        // renewals are made again on the next connection
+        let pending = std::mem::take(&mut self.pending);
+        for call in pending.into_values().filter(|call| call.renews.is_none()) {
+            self.send(&lost(&call.id, "The upstream connection was lost"));
+            self.record(
+                &JsonRpcPayload::Single(call.request),
+                CallStatus::UpstreamError,
+            );
+        }
+        if !self.upstream_subscriptions.is_empty() {
+            self.reconnect = Some((Instant::now() + reconnect_delay(0), 0));
+        }
+    }
+
+    /// reconnects the lost upstream of the client's subscriptions and renews them,
+    /// failed attempts are retried later so the session keeps serving the client meanwhile
+    async fn resubscribe(&mut self, attempt: u32) {
+        self.reconnect = None;
+        if self.upstream.is_none() && !self.connect().await {
+            let attempt = attempt + 1;
+            if attempt < RECONNECT_ATTEMPTS {
+                self.reconnect = Some((Instant::now() + reconnect_delay(attempt), attempt));
+                return;
+            }
+            error!(
+                "Gave up renewing the subscriptions of a {} session",
+                self.chain
+            );
+            self.upstream_subscriptions.clear();
+            let _ = self.outbound.send(upstream_gone());
+            return;
+        }
+        self.renew().await;
+    }
+
+    async fn renew(&mut self) {
+        for text in self.renewals() {
+            self.send_upstream(text).await;
+        }
+    }
+
+    /// the subscribe calls renewing every subscription on a new upstream
+    fn renewals(&mut self) -> Vec<String> {
+        let mut renewals = Vec::new();
+        for subscription in &self.upstream_subscriptions {
+            let proxy_id = self.next_id;
+            self.next_id += 1;
+            let mut relayed = subscription.request.clone();
+            relayed.id = Some(proxy_id.into());
+            renewals.push(serde_json::to_string(&relayed).unwrap_or_default());
+            self.pending.insert(
+                proxy_id,
+                PendingCall {
+                    id: subscription.request.id.clone().unwrap_or_default(),
+                    request: subscription.request.clone(),
+                    batch: None,
+                    renews: Some(subscription.client_id.clone()),
+                },
+            );
+        }
+        renewals
+    }
+
+    fn record(&self, payload: &JsonRpcPayload, status: CallStatus) {
+        if let Some(source) = &self.source {
+            USAGE_ROLLUP.record(source, self.chain, payload, status);
+        }
+    }
+
+    /// answers every call of a payload with the same error
+    fn reject(&self, payload: &JsonRpcPayload, e: impl JsonRpcFailure) {
+        let error = |request: &JsonRpcRequest| {
+            JsonRpcErrorResponse::new(request.id.as_ref(), e.code(), e.to_string())
+        };
+        match payload {
+            JsonRpcPayload::Single(request) => self.send(&error(request)),
+            JsonRpcPayload::Batch(batch) => self.send(&batch.iter().map(error).collect::<Vec<_>>()),
+        }
+    }
+
+    fn answer(&self, batch: bool, responses: Vec<Value>) {
+        match (batch, responses.as_slice()) {
+            (_, []) => {}
+            (false, [response]) => self.send(response),
+            _ => self.send(&responses),
+        }
+    }
+
+    fn send(&self, message: &impl Serialize) {
+        if let Ok(text) = serde_json::to_string(message) {
+            let _ = self.outbound.send(Message::Text(text.into()));
+        }
+    }
 }
 
-fn convert(msg: TungsteniteMessage) -> Option<Message> {
-    match msg {
-        TungsteniteMessage::Text(utf8_bytes) => Some(Message::Text({
-            axum::extract::ws::Utf8Bytes::from(utf8_bytes.as_str())
-        })),
-        TungsteniteMessage::Binary(bytes) => Some(Message::Binary(bytes)),
-        TungsteniteMessage::Ping(bytes) => Some(Message::Ping(bytes)),
-        TungsteniteMessage::Pong(bytes) => Some(Message::Pong(bytes)),
-        TungsteniteMessage::Close(close_frame) => Some(Message::Close({
-            match close_frame {
-                Some(reason) => Some({
-                    info!("WS Connection Closed: {}", reason);
-                    CloseFrame {
-                        code: reason.code.into(),
-                        reason: axum::extract::ws::Utf8Bytes::from(reason.reason.as_str()),
-                    }
-                }),
-                None => None,
+async fn retry(reconnect: Option<(Instant, u32)>) -> u32 {
+    match reconnect {
+        Some((at, attempt)) => {
+            tokio::time::sleep_until(at.into()).await;
+            attempt
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn expire(deadline: Option<(Instant, Expiry)>) -> Expiry {
+    match deadline {
+        Some((at, expiry)) => {
+            tokio::time::sleep_until(at.into()).await;
+            expiry
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_upstream(
+    upstream: &mut Option<Upstream>,
+) -> Option<Result<TungsteniteMessage, tokio_tungstenite::tungstenite::Error>> {
+    match upstream {
+        Some(upstream) => upstream.rv.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// subscribe calls of any chain, like eth_subscribe or accountSubscribe
+fn is_subscribe(method: &str) -> bool {
+    let method = method.to_ascii_lowercase();
+    method.contains("subscribe") && !method.contains("unsubscribe")
+}
+
+fn is_unsubscribe(method: &str) -> bool {
+    method.to_ascii_lowercase().contains("unsubscribe")
+}
+
+fn proxy_id(response: &Value) -> Option<u64> {
+    response.get("id")?.as_u64()
+}
+
+fn lost(id: &Value, message: &str) -> Value {
+    json!(JsonRpcErrorResponse::new(Some(id), UPSTREAM_ERROR, message))
+}
+
+/// Time a client has to send its first request after connecting
+pub static FIRST_REQUEST_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| seconds_var("WS_FIRST_REQUEST_TIMEOUT", 10));
+/// How often the proxy pings clients
+pub static PING_INTERVAL: LazyLock<Duration> =
+    LazyLock::new(|| seconds_var("WS_PING_INTERVAL", 30).max(Duration::from_secs(1)));
+/// Time a client has to answer a ping before it is considered gone
+pub static PONG_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| seconds_var("WS_PONG_TIMEOUT", 10));
+/// Time a client without subscriptions may go without a request
+pub static IDLE_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| seconds_var("WS_IDLE_TIMEOUT", 600));
+
+fn seconds_var(name: &str, default: u64) -> Duration {
+    Duration::from_secs(
+        dotenvy::var(name)
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(default),
+    )
+}
+
+/// Times a lost upstream is reconnected before its subscriptions are given up
+pub const RECONNECT_ATTEMPTS: u32 = 5;
+
+/// waits twice as long after every failed attempt, up to 8 seconds
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    Duration::from_millis(500 << attempt.min(4))
+}
+
+/// connects to the first available websocket upstream of the chain, in health order
+pub async fn connect_node(path: PoktChains) -> Option<NodeSocket> {
+    let config = WebSocketConfig::default().max_message_size(Some(16 * 1024 * 1024));
+    if cfg!(feature = "dev") {
+        let url = dotenvy::var("SEPOLIA_WS").unwrap().parse().unwrap();
+        let request = ClientRequestBuilder::new(url);
+        return match connect_async_tls_with_config(request, Some(config), false, None).await {
+            Ok((node_socket, _res)) => Some(node_socket),
+            Err(e) => {
+                warn!("Failed to connect to websocket: {e}");
+                None
             }
-        })),
-        TungsteniteMessage::Frame(_) => None,
+        };
     }
+
+    for upstream in select_ws(path) {
+        let Some(Ok(url)) = upstream.ws_url.as_ref().map(|url| url.parse()) else {
+            continue;
+        };
+        let mut request = ClientRequestBuilder::new(url);
+        if let Some(service_id) = upstream.service_id {
+            request = request.with_header("Target-Service-Id", String::from(service_id));
+        }
+
+        let started = Instant::now();
+        match connect_async_tls_with_config(request, Some(config), false, None).await {
+            Ok((node_socket, _res)) => {
+                upstream.health.record_success(started.elapsed());
+                return Some(node_socket);
+            }
+            Err(e) => {
+                upstream.health.record_failure();
+                warn!(
+                    "Failed to connect to websocket upstream {}: {e}",
+                    upstream.url
+                );
+            }
+        }
+    }
+    None
 }
 
 #[derive(Debug, Error)]
@@ -201,16 +942,210 @@ pub enum WsError {
     MissingRoute,
     #[error("Route unsupported or malformed")]
     InvalidRoute,
+    #[error("Too many open websocket connections for this api key or plan.")]
+    TooManyConnections,
+}
+
+impl JsonRpcFailure for WsError {
+    fn code(&self) -> i64 {
+        match self {
+            WsError::TooManyConnections => LIMIT_EXCEEDED,
+            _ => INVALID_REQUEST,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            WsError::TooManyConnections => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
 }
 
 impl IntoResponse for WsError {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+        RpcError::new(self, RequestIds::Unknown).into_response()
     }
 }
 
-pub enum Command {
-    Kill,
-    Pong,
-    Ping,
+pub type NodeSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[cfg(test)]
+pub mod test {
+    use super::{Expiry, Session, UpstreamSubscription};
+    use crate::{
+        database::types::Plan,
+        routes::{
+            api_keys::{ApiKeyHash, KeyScope},
+            relayer::{
+                queue::{ClientQueue, OverflowPolicy},
+                router::{AccountUsage, CALL_CACHE, CachedKeyRow},
+                types::{JsonRpcPayload, PoktChains},
+            },
+            usage::UsageSource,
+        },
+    };
+    use axum::extract::ws::Message;
+    use serde_json::{Value, json};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use time::OffsetDateTime;
+
+    fn session(chain: PoktChains) -> (Session, Arc<ClientQueue>) {
+        let queue = Arc::new(ClientQueue::new(16, OverflowPolicy::Disconnect));
+        (Session::new(chain, None, None, None, queue.clone()), queue)
+    }
+
+    fn next_frame(queue: &ClientQueue) -> Value {
+        let Some(Message::Text(text)) = queue.try_pop() else {
+            panic!("no frame for the client");
+        };
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn correlates_responses_by_id() {
+        let (mut session, queue) = session(PoktChains::Base);
+        let payload = |value: Value| -> JsonRpcPayload { serde_json::from_value(value).unwrap() };
+        let proxy_ids = |text: String| -> Vec<Value> {
+            let relayed: Value = serde_json::from_str(&text).unwrap();
+            match relayed {
+                Value::Array(calls) => calls.iter().map(|call| call["id"].clone()).collect(),
+                call => vec![call["id"].clone()],
+            }
+        };
+
+        // clients may reuse ids, the upstream only ever sees ids of the proxy
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "eth_blockNumber", "id": 1},
+            {"jsonrpc": "2.0", "method": "eth_chainId", "id": "b"},
+        ]);
+        let first = proxy_ids(session.register(payload(batch.clone()), Vec::new()));
+        let second = proxy_ids(session.register(payload(batch), Vec::new()));
+        assert!(second.iter().all(|id| !first.contains(id)));
+
+        // batches are answered as a whole, in whatever order the upstream answered them
+        let response = json!([
+            {"jsonrpc": "2.0", "id": second[1], "result": "0x2105"},
+            {"jsonrpc": "2.0", "id": second[0], "result": "0x1"},
+        ]);
+        session.handle_upstream(&response.to_string());
+        let answered = next_frame(&queue);
+        assert_eq!(answered[0]["id"], "b");
+        assert_eq!(answered[1]["id"], 1);
+
+        let single = json!({"jsonrpc": "2.0", "method": "eth_call", "id": 1});
+        let single = proxy_ids(session.register(payload(single), Vec::new()));
+        let response = json!({"jsonrpc": "2.0", "id": single[0], "result": "0x"});
+        session.handle_upstream(&response.to_string());
+        assert_eq!(next_frame(&queue)["id"], 1);
+
+        // notifications of upstream subscriptions pass through untouched
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {"subscription": "0x1", "result": {}},
+        });
+        session.handle_upstream(&notification.to_string());
+        assert_eq!(next_frame(&queue), notification);
+    }
+
+    #[test]
+    fn renews_subscriptions_under_the_same_id() {
+        let (mut session, queue) = session(PoktChains::Solana);
+        let subscribe: JsonRpcPayload = serde_json::from_value(json!({
+            "jsonrpc": "2.0", "method": "slotSubscribe", "id": 1
+        }))
+        .unwrap();
+        let relayed: Value =
+            serde_json::from_str(&session.register(subscribe, Vec::new())).unwrap();
+        let response = json!({"jsonrpc": "2.0", "id": relayed["id"], "result": 0});
+        session.handle_upstream(&response.to_string());
+        assert_eq!(next_frame(&queue)["result"], 0);
+
+        // the upstream is lost and the subscription renewed under a new upstream id
+        session.upstream_lost();
+        assert!(matches!(session.reconnect, Some((_, 0))));
+        let renewal: Value = serde_json::from_str(&session.renewals()[0]).unwrap();
+        assert_eq!(renewal["method"], "slotSubscribe");
+        let response = json!({"jsonrpc": "2.0", "id": renewal["id"], "result": 5});
+        session.handle_upstream(&response.to_string());
+        assert!(queue.try_pop().is_none());
+
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "slotNotification",
+            "params": {"subscription": 5, "result": {"slot": 1}},
+        });
+        session.handle_upstream(&notification.to_string());
+        assert_eq!(next_frame(&queue)["params"]["subscription"], 0);
+
+        // unsubscribing goes to the id of the upstream
+        let unsubscribe: JsonRpcPayload = serde_json::from_value(json!({
+            "jsonrpc": "2.0", "method": "slotUnsubscribe", "params": [0], "id": 2
+        }))
+        .unwrap();
+        let relayed: Value =
+            serde_json::from_str(&session.register(unsubscribe, Vec::new())).unwrap();
+        assert_eq!(relayed["params"][0], 5);
+        assert!(session.upstream_subscriptions.is_empty());
+    }
+
+    #[test]
+    fn quiet_sessions_expire() {
+        let (mut session, _queue) = session(PoktChains::Base);
+        let expiry = |session: &Session| session.deadline().map(|(_, expiry)| expiry);
+        assert_eq!(expiry(&session), Some(Expiry::NoRequest));
+
+        session.last_request = Some(Instant::now());
+        assert_eq!(expiry(&session), Some(Expiry::Idle));
+
+        // an unanswered ping ends the session before it would idle out
+        session.pong_deadline = Some(Instant::now() + Duration::from_secs(1));
+        assert_eq!(expiry(&session), Some(Expiry::Dead));
+
+        session.pong_deadline = None;
+        session.upstream_subscriptions.push(UpstreamSubscription {
+            client_id: json!(1),
+            upstream_id: json!(1),
+            request: serde_json::from_value(json!({"jsonrpc": "2.0", "method": "slotSubscribe"}))
+                .unwrap(),
+        });
+        assert_eq!(expiry(&session), None);
+    }
+
+    #[tokio::test]
+    async fn expired_keys_end_sessions() {
+        let expires = OffsetDateTime::now_utc() + time::Duration::days(1);
+        let row = |expires| CachedKeyRow {
+            prefix: "ws-expired".to_string(),
+            hash: ApiKeyHash::new("ws-expired"),
+            expires,
+            scope: KeyScope::default(),
+            lineage: "ws-expired".to_string(),
+            cap: None,
+            calls: 0,
+            usage: AccountUsage::new("ws@aol.com".to_string(), Plan::Free, expires.unwrap(), 0, 0),
+        };
+        CALL_CACHE.insert(row(Some(expires)));
+        let queue = Arc::new(ClientQueue::new(16, OverflowPolicy::Disconnect));
+        let source = UsageSource {
+            prefix: "ws-expired".to_string(),
+            email: "ws@aol.com".to_string(),
+        };
+        let mut session = Session::new(PoktChains::Base, None, None, Some(source), queue.clone());
+        assert_eq!(session.handle_request(b"{").await, Ok(()));
+        assert!(session.scope.is_some());
+
+        // a key missing from the cache isn't known to be revoked, the session goes on with it
+        CALL_CACHE.remove("ws-expired");
+        assert_eq!(session.handle_request(b"{").await, Ok(()));
+
+        CALL_CACHE.insert(row(Some(
+            OffsetDateTime::now_utc() - time::Duration::minutes(1),
+        )));
+        assert_eq!(session.handle_request(b"{").await, Err(Expiry::KeyInvalid));
+    }
 }
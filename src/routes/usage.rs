@@ -0,0 +1,534 @@
+use super::types::Claims;
+use crate::{
+    database::types::RELATIONAL_DATABASE,
+    middleware::compute_units::{method_compute_units, payload_compute_units},
+    routes::relayer::types::{JsonRpcPayload, PoktChains},
+};
+use axum::{
+    Json,
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use jwt_simple::claims::JWTClaims;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+/// Usage of relayed calls that wasn't written to Postgres yet, flushed with the call cache
+pub static USAGE_ROLLUP: LazyLock<UsageRollup> = LazyLock::new(UsageRollup::default);
+
+/// Most buckets a single series may span
+pub const MAX_BUCKETS: i64 = 1_500;
+pub const DEFAULT_TOP_METHODS: i64 = 10;
+pub const MAX_TOP_METHODS: i64 = 100;
+/// Longest method name that is stored, longer ones are cut
+const MAX_METHOD_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Granularity; 3] = [Granularity::Minute, Granularity::Hour, Granularity::Day];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    pub fn seconds(&self) -> i64 {
+        match self {
+            Granularity::Minute => 60,
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// start of the bucket a unix timestamp falls into, days are UTC days
+    pub fn bucket(&self, unix: i64) -> i64 {
+        unix - unix.rem_euclid(self.seconds())
+    }
+
+    /// range a series covers when none is given
+    fn default_span(&self) -> i64 {
+        match self {
+            Granularity::Minute => 60 * 60,
+            Granularity::Hour => 24 * 60 * 60,
+            Granularity::Day => 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Outcome of a relayed request, calls answered by the node with a JSON-RPC error still succeeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallStatus {
+    Success,
+    UpstreamError,
+}
+
+impl CallStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CallStatus::Success => "success",
+            CallStatus::UpstreamError => "upstream_error",
+        }
+    }
+}
+
+/// The key a request was made with, left in the request extensions by the metering middleware
+#[derive(Debug, Clone)]
+pub struct UsageSource {
+    pub prefix: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RollupKey {
+    pub granularity: Granularity,
+    pub bucket: i64,
+    pub prefix: String,
+    pub email: String,
+    pub chain: PoktChains,
+    pub method: String,
+    pub status: CallStatus,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RollupCounts {
+    pub calls: i64,
+    pub compute_units: i64,
+}
+
+/// Calls aggregated in memory so the relay path never waits on Postgres
+#[derive(Debug, Default)]
+pub struct UsageRollup {
+    pending: Mutex<HashMap<RollupKey, RollupCounts>>,
+}
+
+impl UsageRollup {
+    /// counts every call of a relayed payload into each granularity
+    pub fn record(
+        &self,
+        source: &UsageSource,
+        chain: PoktChains,
+        payload: &JsonRpcPayload,
+        status: CallStatus,
+    ) {
+        self.record_at(
+            OffsetDateTime::now_utc().unix_timestamp(),
+            source,
+            chain,
+            payload,
+            status,
+        );
+    }
+
+    fn record_at(
+        &self,
+        at: i64,
+        source: &UsageSource,
+        chain: PoktChains,
+        payload: &JsonRpcPayload,
+        status: CallStatus,
+    ) {
+        // priced like the metering middleware, single calls pay for every chunk they are split into
+        let calls: Vec<(&str, i64)> = payload
+            .requests()
+            .iter()
+            .map(|request| {
+                let compute_units = match payload {
+                    JsonRpcPayload::Single(_) => payload_compute_units(chain, payload),
+                    JsonRpcPayload::Batch(_) => method_compute_units(&request.method),
+                };
+                (truncate(&request.method), compute_units)
+            })
+            .collect();
+
+        let mut pending = self.pending.lock().unwrap();
+        for (method, compute_units) in calls {
+            for granularity in Granularity::ALL {
+                let counts = pending
+                    .entry(RollupKey {
+                        granularity,
+                        bucket: granularity.bucket(at),
+                        prefix: source.prefix.clone(),
+                        email: source.email.clone(),
+                        chain,
+                        method: method.to_string(),
+                        status,
+                    })
+                    .or_default();
+                counts.calls += 1;
+                counts.compute_units += compute_units;
+            }
+        }
+    }
+
+    /// empties the rollup, returns the usage recorded since the last flush
+    pub fn take(&self) -> HashMap<RollupKey, RollupCounts> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// adds back usage that failed to flush
+    pub fn restore(&self, rollups: HashMap<RollupKey, RollupCounts>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (key, counts) in rollups {
+            let entry = pending.entry(key).or_default();
+            entry.calls += counts.calls;
+            entry.compute_units += counts.compute_units;
+        }
+    }
+}
+
+fn truncate(method: &str) -> &str {
+    match method.char_indices().nth(MAX_METHOD_LEN) {
+        Some((i, _)) => &method[..i],
+        None => method,
+    }
+}
+
+/// writes the usage recorded since the last flush in one round trip
+pub async fn flush_usage() -> Result<(), UsageError> {
+    let rollups = USAGE_ROLLUP.take();
+    if rollups.is_empty() {
+        return Ok(());
+    }
+
+    let mut granularities = Vec::with_capacity(rollups.len());
+    let mut buckets = Vec::with_capacity(rollups.len());
+    let mut prefixes = Vec::with_capacity(rollups.len());
+    let mut emails = Vec::with_capacity(rollups.len());
+    let mut chains = Vec::with_capacity(rollups.len());
+    let mut methods = Vec::with_capacity(rollups.len());
+    let mut statuses = Vec::with_capacity(rollups.len());
+    let mut calls = Vec::with_capacity(rollups.len());
+    let mut compute_units = Vec::with_capacity(rollups.len());
+    for (key, counts) in &rollups {
+        granularities.push(key.granularity.as_str().to_string());
+        buckets.push(key.bucket);
+        prefixes.push(key.prefix.clone());
+        emails.push(key.email.clone());
+        chains.push(key.chain.to_string());
+        methods.push(key.method.clone());
+        statuses.push(key.status.as_str().to_string());
+        calls.push(counts.calls);
+        compute_units.push(counts.compute_units);
+    }
+
+    let res = sqlx::query!(
+        r#"
+            INSERT INTO UsageRollups (
+                granularity, bucket, keyPrefix, customerEmail, chain, method, status,
+                calls, computeUnits
+            )
+            SELECT
+                granularity, to_timestamp(bucket), prefix, email, chain, method, status,
+                calls, compute_units
+            FROM UNNEST(
+                $1::text[], $2::int8[], $3::text[], $4::text[], $5::text[], $6::text[],
+                $7::text[], $8::int8[], $9::int8[]
+            ) AS rollups(
+                granularity, bucket, prefix, email, chain, method, status, calls, compute_units
+            )
+            ON CONFLICT (granularity, bucket, keyPrefix, chain, method, status) DO UPDATE
+            SET
+                calls = UsageRollups.calls + EXCLUDED.calls,
+                computeUnits = UsageRollups.computeUnits + EXCLUDED.computeUnits
+        "#,
+        &granularities,
+        &buckets,
+        &prefixes,
+        &emails,
+        &chains,
+        &methods,
+        &statuses,
+        &calls,
+        &compute_units,
+    )
+    .execute(RELATIONAL_DATABASE.get().unwrap())
+    .await;
+
+    if let Err(e) = res {
+        USAGE_ROLLUP.restore(rollups);
+        Err(e)?
+    }
+
+    Ok(())
+}
+
+/// minute rollups are kept for two days and hourly ones for 90, daily ones are kept for good
+pub async fn prune_usage() -> Result<(), UsageError> {
+    sqlx::query!(
+        r#"
+            DELETE FROM UsageRollups
+            WHERE
+                (granularity = 'minute' AND bucket < now() - INTERVAL '2 days')
+                OR (granularity = 'hour' AND bucket < now() - INTERVAL '90 days')
+        "#
+    )
+    .execute(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
+    Ok(())
+}
+
+/// Filters of the usage endpoints, times are unix timestamps.
+/// Without a range the last hour, day or 30 days are returned depending on the granularity.
+#[derive(Deserialize, Debug, Default)]
+pub struct UsageQuery {
+    #[serde(default)]
+    granularity: Granularity,
+    from: Option<i64>,
+    to: Option<i64>,
+    // key prefix
+    key: Option<String>,
+    chain: Option<String>,
+    method: Option<String>,
+    limit: Option<i64>,
+}
+
+impl UsageQuery {
+    /// the range as whole buckets
+    fn range(&self) -> Result<(OffsetDateTime, OffsetDateTime), UsageError> {
+        let granularity = self.granularity;
+        let to = self
+            .to
+            .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp());
+        let from = self.from.unwrap_or(to - granularity.default_span());
+        // the bucket `to` falls into is included
+        let (from, to) = (
+            granularity.bucket(from),
+            granularity.bucket(to) + granularity.seconds(),
+        );
+        if from >= to || (to - from) / granularity.seconds() > MAX_BUCKETS {
+            Err(UsageError::InvalidRange)?
+        }
+        let timestamp =
+            |unix| OffsetDateTime::from_unix_timestamp(unix).map_err(|_| UsageError::InvalidRange);
+        Ok((timestamp(from)?, timestamp(to)?))
+    }
+}
+
+/// Usage of one bucket, buckets without calls are left out
+#[derive(Serialize, Debug)]
+pub struct UsagePoint {
+    bucket: i64,
+    calls: i64,
+    compute_units: i64,
+    // calls the relay couldn't get an answer for from any upstream
+    errors: i64,
+}
+
+/// usage of the caller over time, optionally narrowed to a key, chain or method
+#[tracing::instrument(skip(jwt))]
+pub async fn get_usage_series(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Query(query): Query<UsageQuery>,
+) -> Result<impl IntoResponse, UsageError> {
+    let (from, to) = query.range()?;
+    let series = sqlx::query_as!(
+        UsagePoint,
+        r#"
+            SELECT
+                extract(epoch FROM bucket)::int8 AS "bucket!",
+                SUM(calls)::int8 AS "calls!",
+                SUM(computeUnits)::int8 AS "compute_units!",
+                COALESCE(SUM(calls) FILTER (WHERE status = 'upstream_error'), 0)::int8 AS "errors!"
+            FROM UsageRollups
+            WHERE
+                customerEmail = $1
+                AND granularity = $2
+                AND bucket >= $3
+                AND bucket < $4
+                AND ($5::text IS NULL OR keyPrefix = $5)
+                AND ($6::text IS NULL OR chain = $6)
+                AND ($7::text IS NULL OR method = $7)
+            GROUP BY bucket
+            ORDER BY bucket
+        "#,
+        jwt.custom.email.as_str(),
+        query.granularity.as_str(),
+        from,
+        to,
+        query.key,
+        query.chain,
+        query.method,
+    )
+    .fetch_all(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
+
+    Ok((StatusCode::OK, Json(series)))
+}
+
+#[derive(Serialize, Debug)]
+pub struct MethodUsage {
+    method: String,
+    calls: i64,
+    compute_units: i64,
+    errors: i64,
+}
+
+/// methods the caller used most in a range, by calls
+#[tracing::instrument(skip(jwt))]
+pub async fn get_top_methods(
+    Extension(jwt): Extension<JWTClaims<Claims<'_>>>,
+    Query(query): Query<UsageQuery>,
+) -> Result<impl IntoResponse, UsageError> {
+    let (from, to) = query.range()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TOP_METHODS)
+        .clamp(1, MAX_TOP_METHODS);
+    let methods = sqlx::query_as!(
+        MethodUsage,
+        r#"
+            SELECT
+                method,
+                SUM(calls)::int8 AS "calls!",
+                SUM(computeUnits)::int8 AS "compute_units!",
+                COALESCE(SUM(calls) FILTER (WHERE status = 'upstream_error'), 0)::int8 AS "errors!"
+            FROM UsageRollups
+            WHERE
+                customerEmail = $1
+                AND granularity = $2
+                AND bucket >= $3
+                AND bucket < $4
+                AND ($5::text IS NULL OR keyPrefix = $5)
+                AND ($6::text IS NULL OR chain = $6)
+            GROUP BY method
+            ORDER BY SUM(calls) DESC, method
+            LIMIT $7
+        "#,
+        jwt.custom.email.as_str(),
+        query.granularity.as_str(),
+        from,
+        to,
+        query.key,
+        query.chain,
+        limit,
+    )
+    .fetch_all(RELATIONAL_DATABASE.get().unwrap())
+    .await?;
+
+    Ok((StatusCode::OK, Json(methods)))
+}
+
+#[derive(Debug, Error)]
+pub enum UsageError {
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("Ranges must end after they start and span at most {MAX_BUCKETS} buckets.")]
+    InvalidRange,
+}
+
+impl IntoResponse for UsageError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            UsageError::InvalidRange => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{CallStatus, Granularity, UsageQuery, UsageRollup, UsageSource};
+    use crate::routes::relayer::{router::parse_payload, types::PoktChains};
+    use serde_json::json;
+
+    #[test]
+    fn rolls_calls_up_per_bucket() {
+        let rollup = UsageRollup::default();
+        let source = UsageSource {
+            prefix: "dd_abcdefghi".to_string(),
+            email: "abc@aol.com".to_string(),
+        };
+        let batch = json!([
+            {"jsonrpc":"2.0","method":"eth_call","params":[],"id": 1},
+            {"jsonrpc":"2.0","method":"eth_call","params":[],"id": 2},
+            {"jsonrpc":"2.0","method":"eth_chainId","id": 3}
+        ]);
+        let payload = parse_payload(batch.to_string().as_bytes()).unwrap();
+        // 2024-01-01T10:15:30Z and a minute later
+        let at = 1_704_104_130;
+        rollup.record_at(at, &source, PoktChains::Base, &payload, CallStatus::Success);
+        rollup.record_at(
+            at + 60,
+            &source,
+            PoktChains::Base,
+            &payload,
+            CallStatus::Success,
+        );
+
+        let rollups = rollup.take();
+        assert!(rollup.take().is_empty());
+        let counts = |granularity: Granularity, method: &str| {
+            rollups
+                .iter()
+                .filter(|(key, _)| key.granularity == granularity && key.method == method)
+                .map(|(key, counts)| (key.bucket, counts.calls, counts.compute_units))
+                .collect::<Vec<_>>()
+        };
+        let mut minutes = counts(Granularity::Minute, "eth_call");
+        minutes.sort();
+        assert_eq!(minutes, vec![(1_704_104_100, 2, 4), (1_704_104_160, 2, 4)]);
+        assert_eq!(
+            counts(Granularity::Hour, "eth_call"),
+            vec![(1_704_103_200, 4, 8)]
+        );
+        assert_eq!(
+            counts(Granularity::Day, "eth_chainId"),
+            vec![(1_704_067_200, 2, 2)]
+        );
+
+        // a failed flush is merged with what was recorded in the meantime
+        let calls: i64 = rollups.values().map(|counts| counts.calls).sum();
+        rollup.restore(rollups.clone());
+        rollup.restore(rollups);
+        let restored = rollup.take();
+        assert_eq!(
+            restored.values().map(|counts| counts.calls).sum::<i64>(),
+            2 * calls
+        );
+    }
+
+    #[test]
+    fn ranges_cover_whole_buckets() {
+        let query = UsageQuery {
+            granularity: Granularity::Hour,
+            from: Some(1_704_104_130),
+            to: Some(1_704_111_330),
+            ..Default::default()
+        };
+        let (from, to) = query.range().unwrap();
+        assert_eq!(from.unix_timestamp(), 1_704_103_200);
+        assert_eq!(to.unix_timestamp(), 1_704_114_000);
+
+        let backwards = UsageQuery {
+            from: Some(1_704_111_330),
+            to: Some(1_704_000_000),
+            ..Default::default()
+        };
+        assert!(backwards.range().is_err());
+        let too_long = UsageQuery {
+            granularity: Granularity::Minute,
+            from: Some(0),
+            to: Some(1_704_000_000),
+            ..Default::default()
+        };
+        assert!(too_long.range().is_err());
+    }
+}